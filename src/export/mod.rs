@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use virt::connect::Connect;
+
+use crate::vm::{types, utils};
+
+/// A running VM created by vm-alloc, with what is needed to log into it.
+struct ManagedHost {
+    name: String,
+    address: String,
+    username: String,
+    ssh_key: Option<String>,
+    tags: Vec<types::Tag>,
+}
+
+/// An Ansible inventory group in the YAML layout.
+#[derive(Serialize, Default)]
+struct InventoryGroup {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    hosts: BTreeMap<String, BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    children: BTreeMap<String, InventoryGroup>,
+}
+
+fn managed_hosts() -> Vec<ManagedHost> {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domains = conn
+        .list_all_domains(virt_sys::VIR_CONNECT_LIST_DOMAINS_ACTIVE)
+        .unwrap();
+
+    let mut hosts = Vec::new();
+    for domain in domains {
+        let name = domain.get_name().unwrap();
+        let Some(metadata) = utils::get_vm_metadata(&domain) else {
+            continue;
+        };
+        let address = utils::guest_addresses(&domain)
+            .into_iter()
+            .find(|a| a.address.parse::<std::net::Ipv4Addr>().is_ok());
+        let Some(address) = address else {
+            eprintln!("Skipping {}: no IP address found", name);
+            continue;
+        };
+        hosts.push(ManagedHost {
+            name,
+            address: address.address,
+            username: metadata.username,
+            ssh_key: metadata.ssh_key,
+            tags: metadata.tags,
+        });
+    }
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+    hosts
+}
+
+/// Ansible group for a tag: `key_value`, or `key` for a bare tag.
+fn group_name(tag: &types::Tag) -> String {
+    let group = if tag.value.is_empty() {
+        tag.name.clone()
+    } else {
+        format!("{}_{}", tag.name, tag.value)
+    };
+    group
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// SSH options matching the ones `vm-alloc ssh` uses.
+fn ssh_options(host: &ManagedHost) -> Vec<(&'static str, String)> {
+    let mut options = vec![
+        ("HostName", host.address.clone()),
+        ("User", host.username.clone()),
+        ("UserKnownHostsFile", utils::known_hosts_path()),
+        ("StrictHostKeyChecking", "accept-new".to_string()),
+        ("HostKeyAlias", host.name.clone()),
+    ];
+    if let Some(key) = &host.ssh_key {
+        options.push(("IdentityFile", key.clone()));
+        options.push(("IdentitiesOnly", "yes".to_string()));
+    }
+    options
+}
+
+fn host_vars(host: &ManagedHost) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    vars.insert("ansible_host".to_string(), host.address.clone());
+    vars.insert("ansible_user".to_string(), host.username.clone());
+    if let Some(key) = &host.ssh_key {
+        vars.insert("ansible_ssh_private_key_file".to_string(), key.clone());
+    }
+    vars.insert(
+        "ansible_ssh_common_args".to_string(),
+        format!(
+            "-o UserKnownHostsFile={} -o StrictHostKeyChecking=accept-new -o HostKeyAlias={}",
+            utils::known_hosts_path(),
+            host.name
+        ),
+    );
+    vars
+}
+
+pub fn ssh_config() {
+    for host in managed_hosts() {
+        println!("Host {}", host.name);
+        for (option, value) in ssh_options(&host) {
+            println!("    {} {}", option, value);
+        }
+        println!();
+    }
+}
+
+pub fn ansible_inventory(format: &str) {
+    let hosts = managed_hosts();
+
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for host in &hosts {
+        for tag in &host.tags {
+            groups.entry(group_name(tag)).or_default().push(&host.name);
+        }
+    }
+
+    if format == "yaml" {
+        let mut all = InventoryGroup::default();
+        for host in &hosts {
+            all.hosts.insert(host.name.clone(), host_vars(host));
+        }
+        for (group, members) in groups {
+            let children = all.children.entry(group).or_default();
+            for member in members {
+                children.hosts.insert(member.to_string(), BTreeMap::new());
+            }
+        }
+        let inventory = BTreeMap::from([("all", all)]);
+        print!(
+            "{}",
+            serde_yml::to_string(&inventory).expect("Failed to serialize inventory to YAML")
+        );
+    } else {
+        println!("[all]");
+        for host in &hosts {
+            let vars: Vec<String> = host_vars(host)
+                .into_iter()
+                .map(|(k, v)| {
+                    if v.contains(' ') {
+                        format!("{}='{}'", k, v)
+                    } else {
+                        format!("{}={}", k, v)
+                    }
+                })
+                .collect();
+            println!("{} {}", host.name, vars.join(" "));
+        }
+        for (group, members) in groups {
+            println!();
+            println!("[{}]", group);
+            for member in members {
+                println!("{}", member);
+            }
+        }
+    }
+}
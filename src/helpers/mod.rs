@@ -1,4 +1,4 @@
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
 use serde_json::Value;
 use xmltree::{Element, XMLNode};
 
@@ -69,6 +69,48 @@ pub fn struct_to_xml<T: Serialize>(value: &T, root_name: &str) -> String {
     xml_str
 }
 
+/// Parse XML into an element tree. Unlike `xml_to_struct` this keeps every
+/// element and attribute, for editing libvirt XML without dropping what the
+/// structs do not model.
+pub fn parse_xml(xml: &str) -> Element {
+    Element::parse(xml.as_bytes()).unwrap()
+}
+
+/// Write an element tree back as XML.
+pub fn element_to_xml(elem: &Element) -> String {
+    let mut buffer = Vec::new();
+    elem.write_with_config(
+        &mut buffer,
+        xmltree::EmitterConfig::new()
+            .perform_indent(true)
+            .write_document_declaration(false),
+    )
+    .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// The child elements of `elem` named `name`.
+pub fn children_mut<'a>(
+    elem: &'a mut Element,
+    name: &'a str,
+) -> impl Iterator<Item = &'a mut Element> {
+    elem.children
+        .iter_mut()
+        .filter_map(move |child| match child {
+            XMLNode::Element(e) if e.name == name => Some(e),
+            _ => None,
+        })
+}
+
+/// Replace the text of the child `name` of `elem`, adding the child if missing.
+pub fn set_child_text(elem: &mut Element, name: &str, text: &str) {
+    if elem.get_child(name).is_none() {
+        elem.children.push(XMLNode::Element(Element::new(name)));
+    }
+    let child = elem.get_mut_child(name).unwrap();
+    child.children = vec![XMLNode::Text(text.to_string())];
+}
+
 /// Recursively convert XML element to JSON value
 fn xml_to_value(elem: &Element) -> Value {
     let mut map = serde_json::Map::new();
@@ -139,3 +181,66 @@ fn normalize_value(value: Value) -> Value {
         other => other,
     }
 }
+
+/// Deserialize a list field from converted XML. A tag that appears only once is
+/// decoded as a single value rather than an array, so accept both forms.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        Many(Vec<T>),
+        One(T),
+    }
+
+    Ok(match Option::<OneOrMany<T>>::deserialize(deserializer)? {
+        Some(OneOrMany::Many(items)) => items,
+        Some(OneOrMany::One(item)) => vec![item],
+        None => Vec::new(),
+    })
+}
+
+/// Print rows as a left-aligned table, with column widths fitted to the content
+pub fn print_table(headers: &[&str], rows: &[Vec<String>], no_headers: bool) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    if !no_headers {
+        print_row(headers.to_vec());
+    }
+    for row in rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// Print rows as CSV, quoting cells that need it
+pub fn print_csv(headers: &[&str], rows: &[Vec<String>], no_headers: bool) {
+    let quote = |cell: &str| {
+        if cell.contains([',', '"', '\n']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell.to_string()
+        }
+    };
+    if !no_headers {
+        println!("{}", headers.join(","));
+    }
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|c| quote(c)).collect();
+        println!("{}", cells.join(","));
+    }
+}
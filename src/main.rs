@@ -1,7 +1,17 @@
-use clap::{Parser, Subcommand};
-use vm::{boot_vm, create_vm, delete_vm, list_vms, restart_vm, shutdown_vm, vm_info};
+use clap::{Args, Parser, Subcommand};
+use std::net::Ipv4Addr;
+use vm::{
+    CreateVmOptions, ListOptions, attach_nic, boot_vm, clone_vm, console_vm, copy_file, create_vm,
+    delete_vm, detach_nic, display_vm, exec_vm, list_vms, logs_vm, pause_vm, rename_vm,
+    resize_disk, resize_vm, restart_vm, restore_vm, resume_vm, save_vm, shutdown_vm, snapshot,
+    ssh_vm, startup, stats_vm, template, throttle_disk, top_vms, types, utils, vm_info, vm_ip,
+    watch_events,
+};
 
+pub mod export;
 pub mod helpers;
+pub mod metrics;
+pub mod network;
 pub mod vm;
 /// Search for a pattern in a file and display the lines that contain it.
 #[derive(Parser)]
@@ -33,11 +43,94 @@ enum Commands {
         #[arg(short, long, default_value = "3")]
         vcpus: u8,
 
+        /// Memory in MiB the VM can be grown to with `resize` while running
+        #[arg(long)]
+        max_memory: Option<u64>,
+
+        /// vCPUs the VM can be grown to with `resize` while running
+        #[arg(long)]
+        max_vcpus: Option<u8>,
+
         /// Disk size in GB
         #[arg(short, long, default_value = "10")]
         disk_size: u64,
+
+        /// Template, or cloud image file in /var/lib/libvirt/images/iso, to back
+        /// the root disk by
+        #[arg(long, default_value = utils::DEFAULT_CLOUD_IMAGE)]
+        image: String,
+
+        /// Limit the root disk to this many IO operations per second
+        #[arg(long)]
+        disk_iops: Option<u64>,
+
+        /// Limit the root disk to this many bytes per second
+        #[arg(long)]
+        disk_bps: Option<u64>,
+
+        /// Add a NIC: network=<name>, bridge=<name>, direct=<dev> or user, optionally
+        /// followed by ,model=<model>,mac=<address>. Repeat for several NICs.
+        #[arg(long = "nic", value_parser = utils::parse_nic)]
+        nics: Vec<types::Interface>,
+
+        /// Wait for the VM to get an IP address and print it
+        #[arg(long)]
+        wait: bool,
+
+        /// Seconds to wait for an IP address with --wait
+        #[arg(long, default_value = "300")]
+        wait_timeout: u64,
+
+        /// Private SSH key whose .pub file is authorized in the VM. Defaults to a
+        /// key generated by vm-alloc.
+        #[arg(long)]
+        ssh_key: Option<String>,
+
+        /// Label the VM with key=value or a bare key. Repeat for several tags.
+        #[arg(long = "tag", value_parser = utils::parse_tag)]
+        tags: Vec<types::Tag>,
+
+        /// Boot the VM when the host boots
+        #[arg(long)]
+        autostart: bool,
+
+        /// Do not set up the QEMU guest agent
+        #[arg(long)]
+        no_guest_agent: bool,
+
+        /// Graphical display of the VM, none for headless VMs
+        #[arg(long, default_value = "vnc", value_parser = ["none", "vnc", "spice"])]
+        graphics: String,
+
+        /// Address the display listens on
+        #[arg(long)]
+        graphics_listen: Option<String>,
+
+        /// Password required to connect to the display
+        #[arg(long)]
+        graphics_password: Option<String>,
+    },
+    List {
+        /// Output format
+        #[arg(short, long, default_value = "table", value_parser = ["table", "json", "yaml", "csv"])]
+        output: String,
+
+        /// Only show VMs in this state
+        #[arg(long, value_parser = ["running", "paused", "saved", "stopped"])]
+        state: Option<String>,
+
+        /// Only show VMs with this tag, as key=value or key (repeatable)
+        #[arg(long = "filter", value_parser = utils::parse_tag)]
+        filters: Vec<types::Tag>,
+
+        /// Column to sort by
+        #[arg(long, default_value = "name", value_parser = ["name", "state", "vcpus", "memory", "age"])]
+        sort: String,
+
+        /// Omit the header row of table and CSV output
+        #[arg(long)]
+        no_headers: bool,
     },
-    List,
     Delete {
         /// Name of the VM
         #[arg(short, long)]
@@ -49,11 +142,123 @@ enum Commands {
         name: String,
     },
     Shutdown {
+        /// Name of the VM
+        #[arg(short, long, required_unless_present = "all")]
+        name: Option<String>,
+
+        /// Shut down every running VM in parallel
+        #[arg(long, conflicts_with = "name")]
+        all: bool,
+
+        /// How to ask the guest to shut down
+        #[arg(long, default_value = "acpi", value_parser = ["acpi", "agent", "signal"])]
+        mode: String,
+
+        /// Seconds to wait for a graceful shutdown
+        #[arg(long, default_value = "10")]
+        timeout: u64,
+
+        /// Fail instead of powering off VMs that do not stop in time
+        #[arg(long)]
+        no_force: bool,
+    },
+    Restart {
         /// Name of the VM
         #[arg(short, long)]
         name: String,
     },
-    Restart {
+    /// Create a new VM from the disks and settings of a stopped one
+    Clone {
+        /// Name of the VM to clone
+        source: String,
+
+        /// Name of the new VM
+        destination: String,
+
+        /// Back the new disks by the source disks instead of copying them. The
+        /// source moves onto overlays so both VMs share read-only bases.
+        #[arg(long)]
+        linked: bool,
+
+        /// Password for the user of the new VM
+        #[arg(short, long, default_value = "123456789")]
+        password: String,
+
+        /// Boot the new VM
+        #[arg(long)]
+        start: bool,
+    },
+    /// Rename a stopped VM along with its disk images
+    Rename {
+        /// Current name of the VM
+        old: String,
+
+        /// New name of the VM
+        new: String,
+    },
+    /// Change the memory and vCPUs of a VM
+    Resize {
+        /// Name of the VM
+        name: String,
+
+        /// Memory in MiB
+        #[arg(short, long, required_unless_present = "vcpus")]
+        memory: Option<u64>,
+
+        /// Number of vCPUs
+        #[arg(short, long)]
+        vcpus: Option<u32>,
+
+        /// Only change the running VM
+        #[arg(long)]
+        live: bool,
+
+        /// Only change the configuration used on the next boot
+        #[arg(long)]
+        config: bool,
+    },
+    /// Turn booting a VM when the host boots on or off
+    Autostart {
+        /// Name of the VM
+        name: String,
+
+        #[arg(value_parser = ["on", "off"])]
+        state: String,
+
+        /// VM that `startup` boots before this one (repeatable)
+        #[arg(long)]
+        after: Vec<String>,
+
+        /// Seconds `startup` waits after booting this VM before the next one
+        #[arg(long)]
+        delay: Option<u64>,
+    },
+    /// Boot a set of VMs in their startup order
+    Startup {
+        /// Only boot VMs with this tag, as key=value or key (repeatable)
+        #[arg(long = "filter", value_parser = utils::parse_tag)]
+        filters: Vec<types::Tag>,
+    },
+    /// Pause the vCPUs of a running VM
+    Pause {
+        /// Name of the VM
+        #[arg(short, long)]
+        name: String,
+    },
+    /// Continue a paused VM
+    Resume {
+        /// Name of the VM
+        #[arg(short, long)]
+        name: String,
+    },
+    /// Save the memory of a VM to disk and stop it
+    Save {
+        /// Name of the VM
+        #[arg(short, long)]
+        name: String,
+    },
+    /// Start a saved VM where it left off
+    Restore {
         /// Name of the VM
         #[arg(short, long)]
         name: String,
@@ -62,9 +267,439 @@ enum Commands {
         /// Name of the VM
         #[arg(short, long)]
         name: String,
+
+        /// Output format
+        #[arg(short, long, default_value = "table", value_parser = ["table", "json", "yaml"])]
+        output: String,
+    },
+    /// Print the IP addresses of a running VM
+    Ip {
+        /// Name of the VM
+        name: String,
+
+        /// Keep polling for up to this many seconds until an address shows up
+        #[arg(long, default_value = "0")]
+        timeout: u64,
+    },
+    /// Connect to a VM over SSH, or run a command in it
+    Ssh {
+        /// Name of the VM
+        name: String,
+
+        /// User to log in as, defaults to the one the VM was created with
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Command to run instead of an interactive shell
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+    /// Run a command in a VM through the guest agent
+    Exec {
+        /// Name of the VM
+        name: String,
+
+        /// Seconds to wait for the command to finish
+        #[arg(long, default_value = "60")]
+        timeout: u64,
+
+        /// Command and its arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Copy a file to or from a VM through the guest agent, e.g. `cp ./f vm:/tmp/f`
+    Cp {
+        /// Local path or <vm>:<path>
+        source: String,
+
+        /// Local path or <vm>:<path>
+        destination: String,
+    },
+    /// Attach to the serial console of a VM, detach with Ctrl+]
+    Console {
+        /// Name of the VM
+        name: String,
+
+        /// Also append console output to this file
+        #[arg(long)]
+        log: Option<String>,
+
+        /// Take over the console from another session
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the serial console log of a VM
+    Logs {
+        /// Name of the VM
+        name: String,
+
+        /// Keep printing new output as it is written
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Print where to connect to the display of a running VM
+    Display {
+        /// Name of the VM
+        name: String,
+    },
+    /// Print the CPU, memory, disk and network usage of a running VM
+    Stats {
+        /// Name of the VM
+        name: String,
+
+        /// Seconds between samples
+        #[arg(short, long, default_value_t = 1)]
+        interval: u64,
+
+        /// Stop after this many samples
+        #[arg(short, long)]
+        count: Option<u64>,
+    },
+    /// Show a refreshing table of the resource usage of all running VMs
+    Top {
+        /// Seconds between refreshes
+        #[arg(short, long, default_value_t = 2)]
+        interval: u64,
+
+        /// Column to sort by
+        #[arg(short, long, default_value = "cpu", value_parser = ["cpu", "memory", "disk", "net", "name"])]
+        sort: String,
+
+        /// Stop after this many refreshes
+        #[arg(short, long)]
+        count: Option<u64>,
+    },
+    /// Print lifecycle, reboot, watchdog, I/O error and guest agent events
+    Events {
+        /// Only show events of this VM
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Output format, `json` prints one object per line
+        #[arg(short, long, default_value = "text", value_parser = ["text", "json"])]
+        output: String,
+    },
+    /// Manage the disks of a VM
+    Disk {
+        #[command(subcommand)]
+        command: DiskCommands,
+    },
+    /// Take, list, revert and delete snapshots of a VM
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommands,
+    },
+    /// Turn VMs into base images for new VMs
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommands,
+    },
+    /// Hotplug network interfaces
+    Nic {
+        #[command(subcommand)]
+        command: NicCommands,
+    },
+    /// Manage libvirt virtual networks
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
+    },
+    /// Inspect DHCP reservations made for VMs
+    Ipam {
+        #[command(subcommand)]
+        command: IpamCommands,
+    },
+    /// Generate configuration for other tools from the managed VMs
+    Export {
+        #[command(subcommand)]
+        command: ExportCommands,
+    },
+    /// Expose VM and vm-alloc metrics to Prometheus
+    Metrics {
+        #[command(subcommand)]
+        command: MetricsCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum MetricsCommands {
+    /// Serve metrics in the Prometheus text format on /metrics
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:9178")]
+        listen: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExportCommands {
+    /// Print ~/.ssh/config entries for the running VMs
+    SshConfig,
+    /// Print an Ansible inventory of the running VMs, grouped by tag
+    AnsibleInventory {
+        /// Inventory format
+        #[arg(long, default_value = "ini", value_parser = ["ini", "yaml"])]
+        format: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum IpamCommands {
+    /// List reserved addresses
+    List {
+        /// Only show reservations on this network
+        #[arg(long)]
+        network: Option<String>,
     },
 }
 
+#[derive(Subcommand)]
+enum NetworkCommands {
+    /// Define and start a new network
+    Create {
+        /// Name of the network
+        name: String,
+
+        /// Forwarding mode of the network
+        #[arg(long, default_value = "nat", value_parser = ["nat", "routed", "isolated"])]
+        mode: String,
+
+        /// IPv4 subnet of the network, e.g. 192.168.100.0/24
+        #[arg(long)]
+        subnet: String,
+
+        /// Name of the host bridge, chosen by libvirt when omitted
+        #[arg(long)]
+        bridge: Option<String>,
+
+        /// First address handed out by DHCP
+        #[arg(long)]
+        dhcp_start: Option<Ipv4Addr>,
+
+        /// Last address handed out by DHCP
+        #[arg(long)]
+        dhcp_end: Option<Ipv4Addr>,
+
+        /// Start the network when the host boots
+        #[arg(long)]
+        autostart: bool,
+    },
+    List,
+    /// Stop and undefine a network
+    Delete {
+        /// Name of the network
+        name: String,
+    },
+    Start {
+        /// Name of the network
+        name: String,
+    },
+    Stop {
+        /// Name of the network
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum NicCommands {
+    /// Attach a NIC to a VM
+    Attach {
+        /// Name of the VM
+        name: String,
+
+        /// NIC specification, same format as `create --nic`
+        #[arg(value_parser = utils::parse_nic)]
+        nic: types::Interface,
+
+        /// Also persist the NIC in the VM configuration
+        #[arg(long)]
+        config: bool,
+    },
+    /// Detach a NIC from a VM
+    Detach {
+        /// Name of the VM
+        name: String,
+
+        /// MAC address of the NIC
+        mac: String,
+
+        /// Also remove the NIC from the VM configuration
+        #[arg(long)]
+        config: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum TemplateCommands {
+    /// Shut a VM down and save its disk, stripped of its identity, as a template
+    Create {
+        /// Name of the VM
+        name: String,
+
+        /// Name of the template, to use with `create --image`
+        template: String,
+    },
+    /// List templates
+    List,
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommands {
+    /// Take a snapshot, including the memory of a running VM
+    Create {
+        /// Name of the VM
+        name: String,
+
+        /// Name of the snapshot
+        snapshot: String,
+
+        /// Free-form description
+        #[arg(long)]
+        description: Option<String>,
+
+        /// Put the disks behind new qcow2 overlays instead of snapshotting
+        /// them inside their images
+        #[arg(long)]
+        external: bool,
+
+        /// Leave out the memory of a running VM
+        #[arg(long, requires = "external")]
+        disk_only: bool,
+    },
+    /// List the snapshots of a VM
+    List {
+        /// Name of the VM
+        name: String,
+
+        /// Show snapshots as a tree of parents and children
+        #[arg(long)]
+        tree: bool,
+    },
+    /// Revert a VM to a snapshot
+    Revert {
+        /// Name of the VM
+        name: String,
+
+        /// Name of the snapshot
+        snapshot: String,
+
+        /// Start the VM after reverting, whatever state the snapshot has
+        #[arg(long, conflicts_with = "paused")]
+        running: bool,
+
+        /// Leave the VM paused after reverting
+        #[arg(long)]
+        paused: bool,
+    },
+    /// Delete a snapshot
+    Delete {
+        /// Name of the VM
+        name: String,
+
+        /// Name of the snapshot
+        snapshot: String,
+
+        /// Also delete the snapshots based on it
+        #[arg(long)]
+        children: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DiskCommands {
+    /// Apply IO limits to a disk
+    Throttle {
+        /// Name of the VM
+        name: String,
+
+        /// Target device of the disk, e.g. vda
+        dev: String,
+
+        #[command(flatten)]
+        limits: IotuneArgs,
+
+        /// Also persist the limits in the VM configuration
+        #[arg(long)]
+        config: bool,
+    },
+    /// Grow a disk, or shrink it with --allow-shrink
+    Resize {
+        /// Name of the VM
+        name: String,
+
+        /// Target device of the disk, e.g. vda
+        dev: String,
+
+        /// New size, e.g. 20G or 512M; a bare number is in GB
+        #[arg(value_parser = utils::parse_size)]
+        size: u64,
+
+        /// Allow making the disk smaller, which loses data past the new end
+        #[arg(long)]
+        allow_shrink: bool,
+    },
+}
+
+#[derive(Args)]
+struct IotuneArgs {
+    /// Total bytes per second
+    #[arg(long)]
+    total_bytes_sec: Option<u64>,
+    /// Read bytes per second
+    #[arg(long)]
+    read_bytes_sec: Option<u64>,
+    /// Write bytes per second
+    #[arg(long)]
+    write_bytes_sec: Option<u64>,
+    /// Total IO operations per second
+    #[arg(long)]
+    total_iops_sec: Option<u64>,
+    /// Read IO operations per second
+    #[arg(long)]
+    read_iops_sec: Option<u64>,
+    /// Write IO operations per second
+    #[arg(long)]
+    write_iops_sec: Option<u64>,
+    /// Burst limit for total bytes per second
+    #[arg(long)]
+    total_bytes_sec_max: Option<u64>,
+    /// Burst limit for read bytes per second
+    #[arg(long)]
+    read_bytes_sec_max: Option<u64>,
+    /// Burst limit for write bytes per second
+    #[arg(long)]
+    write_bytes_sec_max: Option<u64>,
+    /// Burst limit for total IO operations per second
+    #[arg(long)]
+    total_iops_sec_max: Option<u64>,
+    /// Burst limit for read IO operations per second
+    #[arg(long)]
+    read_iops_sec_max: Option<u64>,
+    /// Burst limit for write IO operations per second
+    #[arg(long)]
+    write_iops_sec_max: Option<u64>,
+}
+
+impl From<IotuneArgs> for types::Iotune {
+    fn from(args: IotuneArgs) -> Self {
+        let s = |v: Option<u64>| v.map(|v| v.to_string());
+        types::Iotune {
+            total_bytes_sec: s(args.total_bytes_sec),
+            read_bytes_sec: s(args.read_bytes_sec),
+            write_bytes_sec: s(args.write_bytes_sec),
+            total_iops_sec: s(args.total_iops_sec),
+            read_iops_sec: s(args.read_iops_sec),
+            write_iops_sec: s(args.write_iops_sec),
+            total_bytes_sec_max: s(args.total_bytes_sec_max),
+            read_bytes_sec_max: s(args.read_bytes_sec_max),
+            write_bytes_sec_max: s(args.write_bytes_sec_max),
+            total_iops_sec_max: s(args.total_iops_sec_max),
+            read_iops_sec_max: s(args.read_iops_sec_max),
+            write_iops_sec_max: s(args.write_iops_sec_max),
+        }
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -76,27 +711,290 @@ fn main() {
             name,
             memory,
             vcpus,
+            max_memory,
+            max_vcpus,
             disk_size,
+            image,
             username,
             password,
+            disk_iops,
+            disk_bps,
+            nics,
+            wait,
+            wait_timeout,
+            ssh_key,
+            tags,
+            autostart,
+            no_guest_agent,
+            graphics,
+            graphics_listen,
+            graphics_password,
         } => {
-            create_vm(&name, &username, &password, memory, vcpus, disk_size);
+            let iotune = if disk_iops.is_some() || disk_bps.is_some() {
+                Some(types::Iotune {
+                    total_iops_sec: disk_iops.map(|v| v.to_string()),
+                    total_bytes_sec: disk_bps.map(|v| v.to_string()),
+                    ..Default::default()
+                })
+            } else {
+                None
+            };
+            create_vm(CreateVmOptions {
+                name,
+                username,
+                password,
+                memory,
+                vcpus,
+                max_memory,
+                max_vcpus,
+                disk_size,
+                image,
+                iotune,
+                interfaces: nics,
+                wait_timeout: wait.then_some(wait_timeout),
+                ssh_key,
+                tags,
+                guest_agent: !no_guest_agent,
+                autostart,
+                graphics,
+                graphics_listen,
+                graphics_password,
+            });
         }
         Commands::Delete { name } => {
             delete_vm(&name);
         }
-        Commands::List => {
-            list_vms();
+        Commands::List {
+            output,
+            state,
+            filters,
+            sort,
+            no_headers,
+        } => {
+            list_vms(&ListOptions {
+                output,
+                state,
+                filters,
+                sort,
+                no_headers,
+            });
         }
         Commands::Restart { name } => {
             restart_vm(&name);
         }
-        Commands::Shutdown { name } => {
-            shutdown_vm(&name);
+        Commands::Clone {
+            source,
+            destination,
+            linked,
+            password,
+            start,
+        } => {
+            clone_vm(&source, &destination, linked, &password, start);
+        }
+        Commands::Rename { old, new } => {
+            rename_vm(&old, &new);
+        }
+        Commands::Resize {
+            name,
+            memory,
+            vcpus,
+            live,
+            config,
+        } => {
+            resize_vm(&name, memory, vcpus, live, config);
+        }
+        Commands::Autostart {
+            name,
+            state,
+            after,
+            delay,
+        } => {
+            startup::set_autostart(&name, state == "on", &after, delay);
+        }
+        Commands::Startup { filters } => {
+            startup::startup_vms(&filters);
+        }
+        Commands::Pause { name } => {
+            pause_vm(&name);
+        }
+        Commands::Resume { name } => {
+            resume_vm(&name);
+        }
+        Commands::Save { name } => {
+            save_vm(&name);
+        }
+        Commands::Restore { name } => {
+            restore_vm(&name);
+        }
+        Commands::Shutdown {
+            name,
+            all: _,
+            mode,
+            timeout,
+            no_force,
+        } => {
+            shutdown_vm(name.as_deref(), &mode, timeout, !no_force);
+        }
+        Commands::VMInfo { name, output } => {
+            vm_info(&name, &output);
+        }
+        Commands::Ip { name, timeout } => {
+            vm_ip(&name, timeout);
+        }
+        Commands::Ssh {
+            name,
+            user,
+            command,
+        } => {
+            ssh_vm(&name, user.as_deref(), &command);
+        }
+        Commands::Exec {
+            name,
+            timeout,
+            command,
+        } => {
+            exec_vm(&name, &command, timeout);
+        }
+        Commands::Cp {
+            source,
+            destination,
+        } => {
+            copy_file(&source, &destination);
+        }
+        Commands::Console { name, log, force } => {
+            console_vm(&name, log.as_deref(), force);
+        }
+        Commands::Logs { name, follow } => {
+            logs_vm(&name, follow);
+        }
+        Commands::Display { name } => {
+            display_vm(&name);
+        }
+        Commands::Stats {
+            name,
+            interval,
+            count,
+        } => {
+            stats_vm(&name, interval, count);
+        }
+        Commands::Top {
+            interval,
+            sort,
+            count,
+        } => {
+            top_vms(interval, &sort, count);
         }
-        Commands::VMInfo { name } => {
-            vm_info(&name);
+        Commands::Events { name, output } => {
+            watch_events(name.as_deref(), &output);
         }
+        Commands::Disk { command } => match command {
+            DiskCommands::Throttle {
+                name,
+                dev,
+                limits,
+                config,
+            } => {
+                throttle_disk(&name, &dev, &limits.into(), config);
+            }
+            DiskCommands::Resize {
+                name,
+                dev,
+                size,
+                allow_shrink,
+            } => {
+                resize_disk(&name, &dev, size, allow_shrink);
+            }
+        },
+        Commands::Template { command } => match command {
+            TemplateCommands::Create { name, template } => {
+                template::create_template(&name, &template);
+            }
+            TemplateCommands::List => {
+                template::list_templates();
+            }
+        },
+        Commands::Snapshot { command } => match command {
+            SnapshotCommands::Create {
+                name,
+                snapshot,
+                description,
+                external,
+                disk_only,
+            } => {
+                snapshot::create_snapshot(&name, &snapshot, description, external, disk_only);
+            }
+            SnapshotCommands::List { name, tree } => {
+                snapshot::list_snapshots(&name, tree);
+            }
+            SnapshotCommands::Revert {
+                name,
+                snapshot,
+                running,
+                paused,
+            } => {
+                snapshot::revert_snapshot(&name, &snapshot, running, paused);
+            }
+            SnapshotCommands::Delete {
+                name,
+                snapshot,
+                children,
+            } => {
+                snapshot::delete_snapshot(&name, &snapshot, children);
+            }
+        },
+        Commands::Nic { command } => match command {
+            NicCommands::Attach { name, nic, config } => {
+                attach_nic(&name, &nic, config);
+            }
+            NicCommands::Detach { name, mac, config } => {
+                detach_nic(&name, &mac, config);
+            }
+        },
+        Commands::Network { command } => match command {
+            NetworkCommands::Create {
+                name,
+                mode,
+                subnet,
+                bridge,
+                dhcp_start,
+                dhcp_end,
+                autostart,
+            } => {
+                network::create_network(
+                    &name, &mode, bridge, &subnet, dhcp_start, dhcp_end, autostart,
+                );
+            }
+            NetworkCommands::List => {
+                network::list_networks();
+            }
+            NetworkCommands::Delete { name } => {
+                network::delete_network(&name);
+            }
+            NetworkCommands::Start { name } => {
+                network::start_network(&name);
+            }
+            NetworkCommands::Stop { name } => {
+                network::stop_network(&name);
+            }
+        },
+        Commands::Ipam { command } => match command {
+            IpamCommands::List { network } => {
+                network::ipam::list_allocations(network.as_deref());
+            }
+        },
+        Commands::Export { command } => match command {
+            ExportCommands::SshConfig => {
+                export::ssh_config();
+            }
+            ExportCommands::AnsibleInventory { format } => {
+                export::ansible_inventory(&format);
+            }
+        },
+        Commands::Metrics { command } => match command {
+            MetricsCommands::Serve { listen } => {
+                metrics::serve(&listen);
+            }
+        },
     }
     ();
 }
@@ -0,0 +1,384 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+use virt::connect::Connect;
+use virt::domain::DomainInfo;
+
+use crate::vm::{stats, utils};
+
+/// Upper bounds, in seconds, of the create duration histogram buckets.
+const CREATE_DURATION_BUCKETS: [f64; 8] = [5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// vm-alloc's own counters. Every CLI run is a separate process, so they are
+/// kept in a file under the state directory.
+#[derive(Serialize, Deserialize, Default)]
+struct Counters {
+    vms_created: u64,
+    vms_deleted: u64,
+    /// Non-cumulative count per entry of `CREATE_DURATION_BUCKETS`, plus one
+    /// for anything slower
+    create_duration_buckets: Vec<u64>,
+    create_duration_sum: f64,
+}
+
+fn counters_path() -> String {
+    format!("{}/metrics.json", utils::STATE_DIR)
+}
+
+fn load_counters() -> Counters {
+    std::fs::read_to_string(counters_path())
+        .ok()
+        .and_then(|c| serde_json::from_str(&c).ok())
+        .unwrap_or_default()
+}
+
+/// Run `update` on the counters under an exclusive lock, so concurrent
+/// vm-alloc runs do not lose each other's increments. The file is replaced
+/// atomically so readers never see it half written.
+fn update_counters(update: impl FnOnce(&mut Counters)) {
+    let result = std::fs::create_dir_all(utils::STATE_DIR).and_then(|_| {
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(format!("{}.lock", counters_path()))?;
+        // Released when `lock` is closed
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut counters = load_counters();
+        update(&mut counters);
+        let temp_path = format!("{}.tmp", counters_path());
+        std::fs::write(&temp_path, serde_json::to_string(&counters).unwrap())?;
+        std::fs::rename(&temp_path, counters_path())
+    });
+    if let Err(e) = result {
+        eprintln!("Warning: could not update metrics counters: {}", e);
+    }
+}
+
+/// Count a created VM and how long creating it took.
+pub fn record_vm_created(duration: Duration) {
+    update_counters(|counters| {
+        let seconds = duration.as_secs_f64();
+        let bucket = CREATE_DURATION_BUCKETS
+            .iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(CREATE_DURATION_BUCKETS.len());
+        counters
+            .create_duration_buckets
+            .resize(CREATE_DURATION_BUCKETS.len() + 1, 0);
+        counters.create_duration_buckets[bucket] += 1;
+        counters.create_duration_sum += seconds;
+        counters.vms_created += 1;
+    });
+}
+
+/// Count a deleted VM.
+pub fn record_vm_deleted() {
+    update_counters(|counters| counters.vms_deleted += 1);
+}
+
+/// Quote a label value as the Prometheus text format expects.
+fn label(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Name, type, help and value of a metric read from `virDomainGetInfo`.
+type InfoMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&DomainInfo) -> f64,
+);
+/// Name, type, help and value of a metric read from a usage sample, if
+/// the hypervisor reports it.
+type SampleMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&stats::Sample) -> Option<f64>,
+);
+
+/// Writes metric families in the Prometheus text exposition format.
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, metric_type).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, label(value)))
+            .collect();
+        if labels.is_empty() {
+            writeln!(self.out, "{} {}", name, value).unwrap();
+        } else {
+            writeln!(self.out, "{}{{{}}} {}", name, labels.join(","), value).unwrap();
+        }
+    }
+}
+
+/// Collect all metrics in the Prometheus text format. Fails only when the
+/// domains cannot be listed; a domain that errors, e.g. because it was just
+/// undefined, is left out.
+pub fn render(conn: &Connect) -> Result<String, String> {
+    let mut exposition = Exposition { out: String::new() };
+    let domains = conn
+        .list_all_domains(0)
+        .map_err(|e| format!("could not list domains: {}", e))?;
+
+    // (name, info, sample) for every domain, sampled once per scrape
+    let samples: Vec<_> = domains
+        .iter()
+        .filter_map(|domain| {
+            let name = domain.get_name().ok()?;
+            let sample = if domain.is_active().ok()? {
+                stats::sample(domain).ok()
+            } else {
+                None
+            };
+            Some((name, domain.get_info().ok(), sample))
+        })
+        .collect();
+
+    let info_metrics: [InfoMetric; 3] = [
+        (
+            "vmalloc_domain_state",
+            "gauge",
+            "libvirt state of the domain: 1 running, 2 blocked, 3 paused, 4 shutting down, 5 shut off, 6 crashed, 7 suspended.",
+            |i| i.state as f64,
+        ),
+        (
+            "vmalloc_domain_vcpus",
+            "gauge",
+            "Number of virtual CPUs of the domain.",
+            |i| i.nr_virt_cpu as f64,
+        ),
+        (
+            "vmalloc_domain_memory_max_bytes",
+            "gauge",
+            "Maximum memory of the domain.",
+            |i| (i.max_mem * 1024) as f64,
+        ),
+    ];
+    for (metric, metric_type, help, value) in info_metrics {
+        exposition.family(metric, metric_type, help);
+        for (name, info, _) in &samples {
+            if let Some(info) = info {
+                exposition.sample(metric, &[("domain", name)], value(info));
+            }
+        }
+    }
+
+    // The remaining domain metrics only exist while a domain runs
+    let running: Vec<(&String, &stats::Sample)> = samples
+        .iter()
+        .filter_map(|(name, _, sample)| Some((name, sample.as_ref()?)))
+        .collect();
+    let sample_metrics: [SampleMetric; 8] = [
+        (
+            "vmalloc_domain_cpu_time_seconds_total",
+            "counter",
+            "CPU time used by all vCPUs of the domain.",
+            |s| Some(s.cpu_time as f64 / 1e9),
+        ),
+        (
+            "vmalloc_domain_memory_actual_bytes",
+            "gauge",
+            "Memory currently given to the guest by the balloon driver.",
+            |s| Some((s.memory.actual * 1024) as f64),
+        ),
+        (
+            "vmalloc_domain_memory_rss_bytes",
+            "gauge",
+            "Resident memory of the QEMU process on the host.",
+            |s| s.memory.rss.map(|v| (v * 1024) as f64),
+        ),
+        (
+            "vmalloc_domain_memory_available_bytes",
+            "gauge",
+            "Memory available to the guest as reported by the guest.",
+            |s| s.memory.available.map(|v| (v * 1024) as f64),
+        ),
+        (
+            "vmalloc_domain_block_read_bytes_total",
+            "counter",
+            "Bytes read from all disks of the domain.",
+            |s| Some(s.disk_read_bytes as f64),
+        ),
+        (
+            "vmalloc_domain_block_write_bytes_total",
+            "counter",
+            "Bytes written to all disks of the domain.",
+            |s| Some(s.disk_write_bytes as f64),
+        ),
+        (
+            "vmalloc_domain_network_receive_bytes_total",
+            "counter",
+            "Bytes received on all interfaces of the domain.",
+            |s| Some(s.net_rx_bytes as f64),
+        ),
+        (
+            "vmalloc_domain_network_transmit_bytes_total",
+            "counter",
+            "Bytes sent on all interfaces of the domain.",
+            |s| Some(s.net_tx_bytes as f64),
+        ),
+    ];
+    for (metric, metric_type, help, value) in sample_metrics {
+        exposition.family(metric, metric_type, help);
+        for (name, sample) in &running {
+            if let Some(value) = value(sample) {
+                exposition.sample(metric, &[("domain", name)], value);
+            }
+        }
+    }
+
+    let counters = load_counters();
+    exposition.family(
+        "vmalloc_vms_created_total",
+        "counter",
+        "VMs created with vm-alloc.",
+    );
+    exposition.sample(
+        "vmalloc_vms_created_total",
+        &[],
+        counters.vms_created as f64,
+    );
+    exposition.family(
+        "vmalloc_vms_deleted_total",
+        "counter",
+        "VMs deleted with vm-alloc.",
+    );
+    exposition.sample(
+        "vmalloc_vms_deleted_total",
+        &[],
+        counters.vms_deleted as f64,
+    );
+
+    exposition.family(
+        "vmalloc_create_duration_seconds",
+        "histogram",
+        "Time from starting `create` until the VM was booted.",
+    );
+    let mut cumulative = 0;
+    for (i, le) in CREATE_DURATION_BUCKETS.iter().enumerate() {
+        cumulative += counters
+            .create_duration_buckets
+            .get(i)
+            .copied()
+            .unwrap_or(0);
+        exposition.sample(
+            "vmalloc_create_duration_seconds_bucket",
+            &[("le", &le.to_string())],
+            cumulative as f64,
+        );
+    }
+    let count: u64 = counters.create_duration_buckets.iter().sum();
+    exposition.sample(
+        "vmalloc_create_duration_seconds_bucket",
+        &[("le", "+Inf")],
+        count as f64,
+    );
+    exposition.sample(
+        "vmalloc_create_duration_seconds_sum",
+        &[],
+        counters.create_duration_sum,
+    );
+    exposition.sample("vmalloc_create_duration_seconds_count", &[], count as f64);
+
+    Ok(exposition.out)
+}
+
+fn handle_request(conn: &Connect, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, no request needs them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match render(conn) {
+            Ok(metrics) => (
+                "200 OK",
+                "text/plain; version=0.0.4; charset=utf-8",
+                metrics,
+            ),
+            Err(e) => (
+                "500 Internal Server Error",
+                "text/plain",
+                format!("{}\n", e),
+            ),
+        },
+        (Some("GET"), Some("/")) => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            "<html><body><a href=\"/metrics\">Metrics</a></body></html>\n".to_string(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serve metrics over HTTP on `listen` until the process is stopped.
+pub fn serve(listen: &str) {
+    let mut conn = Connect::open(Some("qemu:///system")).unwrap();
+    let listener = match TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", listen, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Serving metrics on http://{}/metrics", listen);
+
+    // Scrapes are rare and cheap enough to answer one at a time
+    for stream in listener.incoming() {
+        // Reconnect after libvirtd restarted, failed scrapes get a 500 until then
+        if !conn.is_alive().unwrap_or(false)
+            && let Ok(new_conn) = Connect::open(Some("qemu:///system"))
+        {
+            conn = new_conn;
+        }
+        let result = stream.and_then(|stream| handle_request(&conn, stream));
+        if let Err(e) = result {
+            eprintln!("Metrics request failed: {}", e);
+        }
+    }
+}
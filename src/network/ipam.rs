@@ -0,0 +1,252 @@
+use std::ffi::{CStr, c_char};
+use std::net::Ipv4Addr;
+use virt::connect::Connect;
+use virt::network::Network;
+
+use crate::helpers;
+use crate::network::types;
+
+/// Generate a stable MAC address in the QEMU range for NIC `index` of `vm` on
+/// `network`, so re-creating a VM keeps its DHCP reservation.
+pub fn deterministic_mac(vm: &str, network: &str, index: usize) -> String {
+    // FNV-1a, which unlike the std hasher is stable across releases
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in format!("{}/{}/{}", vm, network, index).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let bytes = hash.to_be_bytes();
+    format!(
+        "52:54:00:{:02x}:{:02x}:{:02x}",
+        bytes[0], bytes[1], bytes[2]
+    )
+}
+
+fn update_flags(network: &Network) -> u32 {
+    if network.is_active().unwrap() {
+        virt_sys::VIR_NETWORK_UPDATE_AFFECT_LIVE | virt_sys::VIR_NETWORK_UPDATE_AFFECT_CONFIG
+    } else {
+        virt_sys::VIR_NETWORK_UPDATE_AFFECT_CONFIG
+    }
+}
+
+fn string_from_ptr(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// `(ip, mac)` of the leases currently handed out by the DHCP server of
+/// `network`.
+fn dhcp_leases(network: &Network) -> Result<Vec<(String, String)>, String> {
+    let mut leases: *mut virt_sys::virNetworkDHCPLeasePtr = std::ptr::null_mut();
+    let count = unsafe {
+        virt_sys::virNetworkGetDHCPLeases(network.as_ptr(), std::ptr::null(), &mut leases, 0)
+    };
+    if count < 0 {
+        return Err(format!(
+            "could not read DHCP leases: {}",
+            virt::error::Error::last_error()
+        ));
+    }
+
+    let mut addresses = Vec::new();
+    for i in 0..count as usize {
+        let lease = unsafe { *leases.add(i) };
+        let (ip, mac) = unsafe {
+            (
+                string_from_ptr((*lease).ipaddr),
+                string_from_ptr((*lease).mac),
+            )
+        };
+        if let Some(ip) = ip {
+            addresses.push((ip, mac.unwrap_or_default()));
+        }
+        unsafe { virt_sys::virNetworkDHCPLeaseFree(lease) };
+    }
+    if !leases.is_null() {
+        unsafe { libc::free(leases as *mut libc::c_void) };
+    }
+    Ok(addresses)
+}
+
+/// Reserve an address for `mac` in the DHCP range of `network_name` and return it.
+/// An existing reservation for the MAC is reused. Returns `None` when the network
+/// does not serve DHCP.
+pub fn reserve_address(
+    conn: &Connect,
+    network_name: &str,
+    vm: &str,
+    mac: &str,
+) -> Result<Option<Ipv4Addr>, String> {
+    let network = Network::lookup_by_name(conn, network_name)
+        .map_err(|e| format!("network {} not found: {}", network_name, e))?;
+    let network_config: types::NetworkConfig =
+        helpers::xml_to_struct(&network.get_xml_desc(0).unwrap());
+
+    let Some((ip, dhcp)) = network_config
+        .ip
+        .iter()
+        .find_map(|ip| ip.dhcp.as_ref().map(|dhcp| (ip, dhcp)))
+    else {
+        return Ok(None);
+    };
+
+    if let Some(host) = dhcp.host.iter().find(|h| {
+        h.mac
+            .as_deref()
+            .is_some_and(|m| m.eq_ignore_ascii_case(mac))
+    }) {
+        return Ok(host.ip.parse().ok());
+    }
+
+    let Some(range) = dhcp.range.first() else {
+        return Ok(None);
+    };
+    let start: Ipv4Addr = range.start.parse().unwrap();
+    let end: Ipv4Addr = range.end.parse().unwrap();
+    // Addresses leased to other guests are taken too, reserving one would
+    // hand it out twice
+    let leases = dhcp_leases(&network)?;
+    let used: Vec<&str> = dhcp
+        .host
+        .iter()
+        .map(|h| h.ip.as_str())
+        .chain([ip.address.as_str()])
+        .chain(
+            leases
+                .iter()
+                .filter(|(_, lease_mac)| !lease_mac.eq_ignore_ascii_case(mac))
+                .map(|(lease_ip, _)| lease_ip.as_str()),
+        )
+        .collect();
+
+    let address = (u32::from(start)..=u32::from(end))
+        .map(Ipv4Addr::from)
+        .find(|a| !used.contains(&a.to_string().as_str()))
+        .ok_or_else(|| format!("no free addresses left in network {}", network_name))?;
+
+    let host = types::DhcpHost {
+        mac: Some(mac.to_string()),
+        name: Some(vm.to_string()),
+        ip: address.to_string(),
+    };
+    network
+        .update(
+            virt_sys::VIR_NETWORK_UPDATE_COMMAND_ADD_LAST,
+            virt_sys::VIR_NETWORK_SECTION_IP_DHCP_HOST,
+            -1,
+            &helpers::struct_to_xml(&host, "host"),
+            update_flags(&network),
+        )
+        .map_err(|e| format!("failed to add DHCP reservation: {}", e))?;
+
+    Ok(Some(address))
+}
+
+/// Remove the DHCP reservations of `mac` from `network_name`.
+pub fn release_address(conn: &Connect, network_name: &str, mac: &str) {
+    let Ok(network) = Network::lookup_by_name(conn, network_name) else {
+        return;
+    };
+    let network_config: types::NetworkConfig =
+        helpers::xml_to_struct(&network.get_xml_desc(0).unwrap());
+
+    let hosts = network_config
+        .ip
+        .into_iter()
+        .flat_map(|ip| ip.dhcp)
+        .flat_map(|dhcp| dhcp.host)
+        .filter(|h| {
+            h.mac
+                .as_deref()
+                .is_some_and(|m| m.eq_ignore_ascii_case(mac))
+        });
+    for host in hosts {
+        match network.update(
+            virt_sys::VIR_NETWORK_UPDATE_COMMAND_DELETE,
+            virt_sys::VIR_NETWORK_SECTION_IP_DHCP_HOST,
+            -1,
+            &helpers::struct_to_xml(&host, "host"),
+            update_flags(&network),
+        ) {
+            Ok(()) => println!("Released {} on network {}", host.ip, network_name),
+            Err(e) => eprintln!("Error releasing {}: {}", host.ip, e),
+        }
+    }
+}
+
+/// Update the name on the DHCP reservation of `mac` in `network_name`, which
+/// dnsmasq hands out as the hostname, after its VM was renamed to `vm`.
+pub fn rename_reservation(
+    conn: &Connect,
+    network_name: &str,
+    mac: &str,
+    vm: &str,
+) -> Result<(), String> {
+    let network = Network::lookup_by_name(conn, network_name)
+        .map_err(|e| format!("network {} not found: {}", network_name, e))?;
+    let network_config: types::NetworkConfig =
+        helpers::xml_to_struct(&network.get_xml_desc(0).unwrap());
+
+    let host = network_config
+        .ip
+        .into_iter()
+        .flat_map(|ip| ip.dhcp)
+        .flat_map(|dhcp| dhcp.host)
+        .find(|h| {
+            h.mac
+                .as_deref()
+                .is_some_and(|m| m.eq_ignore_ascii_case(mac))
+        });
+    let Some(host) = host else {
+        return Ok(());
+    };
+    let host = types::DhcpHost {
+        name: Some(vm.to_string()),
+        ..host
+    };
+    network
+        .update(
+            virt_sys::VIR_NETWORK_UPDATE_COMMAND_MODIFY,
+            virt_sys::VIR_NETWORK_SECTION_IP_DHCP_HOST,
+            -1,
+            &helpers::struct_to_xml(&host, "host"),
+            update_flags(&network),
+        )
+        .map_err(|e| format!("failed to update DHCP reservation: {}", e))
+}
+
+pub fn list_allocations(network_name: Option<&str>) {
+    println!("Listing IP allocations");
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let networks = match network_name {
+        Some(name) => vec![Network::lookup_by_name(&conn, name).unwrap()],
+        None => conn.list_all_networks(0).unwrap(),
+    };
+
+    for network in networks {
+        let name = network.get_name().unwrap();
+        let network_config: types::NetworkConfig =
+            helpers::xml_to_struct(&network.get_xml_desc(0).unwrap());
+        let hosts = network_config
+            .ip
+            .into_iter()
+            .flat_map(|ip| ip.dhcp)
+            .flat_map(|dhcp| dhcp.host);
+        for host in hosts {
+            println!(
+                "Network: {}, Name: {}, MAC: {}, IP: {}",
+                name,
+                host.name.unwrap_or_default(),
+                host.mac.unwrap_or_default(),
+                host.ip
+            );
+        }
+    }
+}
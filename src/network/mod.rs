@@ -0,0 +1,149 @@
+use std::net::Ipv4Addr;
+use virt::connect::Connect;
+use virt::network::Network;
+
+use crate::helpers;
+
+pub mod ipam;
+pub mod types;
+pub mod utils;
+
+pub fn create_network(
+    name: &str,
+    mode: &str,
+    bridge: Option<String>,
+    subnet: &str,
+    dhcp_start: Option<Ipv4Addr>,
+    dhcp_end: Option<Ipv4Addr>,
+    autostart: bool,
+) {
+    println!("Creating network: {}", name);
+
+    let subnet = match utils::parse_subnet(subnet) {
+        Ok(subnet) => subnet,
+        Err(e) => {
+            eprintln!("Error creating network: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let (default_start, default_end) = subnet.dhcp_range();
+    let dhcp_range = (
+        dhcp_start.unwrap_or(default_start),
+        dhcp_end.unwrap_or(default_end),
+    );
+    let netmask = u32::from(subnet.netmask());
+    for address in [dhcp_range.0, dhcp_range.1] {
+        if u32::from(address) & netmask != u32::from(subnet.network) {
+            eprintln!(
+                "DHCP address {} is outside of {}/{}",
+                address, subnet.network, subnet.prefix
+            );
+            std::process::exit(1);
+        }
+        // The network and broadcast addresses cannot be handed out
+        if address < subnet.gateway() || address > default_end {
+            eprintln!(
+                "DHCP address {} is not a usable host address in {}/{}",
+                address, subnet.network, subnet.prefix
+            );
+            std::process::exit(1);
+        }
+    }
+    if dhcp_range.0 > dhcp_range.1 {
+        eprintln!(
+            "DHCP range start {} is after its end {}",
+            dhcp_range.0, dhcp_range.1
+        );
+        std::process::exit(1);
+    }
+    if (dhcp_range.0..=dhcp_range.1).contains(&subnet.gateway()) {
+        eprintln!(
+            "DHCP range {} - {} includes the gateway {}",
+            dhcp_range.0,
+            dhcp_range.1,
+            subnet.gateway()
+        );
+        std::process::exit(1);
+    }
+
+    let network_xml = utils::generate_network_xml(name, mode, bridge, &subnet, dhcp_range);
+
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let network = Network::define_xml(&conn, &network_xml).unwrap();
+    network.create().unwrap();
+    if autostart {
+        network.set_autostart(true).unwrap();
+    }
+
+    println!(
+        "Network {} created on bridge {} with subnet {}/{}",
+        name,
+        network.get_bridge_name().unwrap(),
+        subnet.network,
+        subnet.prefix
+    );
+}
+
+pub fn list_networks() {
+    println!("Listing all networks");
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let networks = conn.list_all_networks(0).unwrap();
+    for network in networks {
+        let name = network.get_name().unwrap();
+        let network_config: types::NetworkConfig =
+            helpers::xml_to_struct(&network.get_xml_desc(0).unwrap());
+
+        let mode = network_config
+            .forward
+            .map(|f| f.mode)
+            .unwrap_or_else(|| "isolated".to_string());
+        let addresses: Vec<String> = network_config
+            .ip
+            .iter()
+            .map(|ip| match (&ip.netmask, &ip.prefix) {
+                (Some(netmask), _) => format!("{}/{}", ip.address, netmask),
+                (None, Some(prefix)) => format!("{}/{}", ip.address, prefix),
+                (None, None) => ip.address.clone(),
+            })
+            .collect();
+
+        println!(
+            "Name: {}, Mode: {}, Bridge: {}, IP: {}, Active: {}, Autostart: {}",
+            name,
+            mode,
+            network.get_bridge_name().unwrap_or_default(),
+            addresses.join(" "),
+            network.is_active().unwrap(),
+            network.get_autostart().unwrap()
+        );
+    }
+}
+
+pub fn delete_network(name: &str) {
+    println!("Deleting network: {}", name);
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let network = Network::lookup_by_name(&conn, name).unwrap();
+    if network.is_active().unwrap() {
+        network.destroy().unwrap();
+    }
+
+    network.undefine().unwrap();
+}
+
+pub fn start_network(name: &str) {
+    println!("Starting network: {}", name);
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let network = Network::lookup_by_name(&conn, name).unwrap();
+    network.create().unwrap();
+}
+
+pub fn stop_network(name: &str) {
+    println!("Stopping network: {}", name);
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let network = Network::lookup_by_name(&conn, name).unwrap();
+    if network.is_active().unwrap() {
+        network.destroy().unwrap();
+    } else {
+        println!("Network {} is not active.", name);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::helpers;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+
+    /// Absent for isolated networks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward: Option<Forward>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<Bridge>,
+
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
+    pub ip: Vec<Ip>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Forward {
+    /// `nat` or `route`
+    #[serde(rename = "@mode")]
+    pub mode: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Bridge {
+    #[serde(rename = "@name", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "@stp", skip_serializing_if = "Option::is_none")]
+    pub stp: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Ip {
+    #[serde(rename = "@address")]
+    pub address: String,
+    #[serde(rename = "@netmask", skip_serializing_if = "Option::is_none")]
+    pub netmask: Option<String>,
+    #[serde(rename = "@prefix", skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhcp: Option<Dhcp>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Dhcp {
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
+    pub range: Vec<DhcpRange>,
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
+    pub host: Vec<DhcpHost>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DhcpRange {
+    #[serde(rename = "@start")]
+    pub start: String,
+    #[serde(rename = "@end")]
+    pub end: String,
+}
+
+/// A static DHCP reservation, `<host mac= ip= name=>`.
+#[derive(Serialize, Deserialize)]
+pub struct DhcpHost {
+    #[serde(rename = "@mac", skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(rename = "@name", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "@ip")]
+    pub ip: String,
+}
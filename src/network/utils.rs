@@ -0,0 +1,93 @@
+use crate::helpers;
+use crate::network::types;
+use std::net::Ipv4Addr;
+use uuid::Uuid;
+
+/// An IPv4 subnet given as `address/prefix`, e.g. `192.168.100.0/24`.
+pub struct Subnet {
+    pub network: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX << (32 - self.prefix))
+    }
+
+    /// The first usable address, which libvirt assigns to the bridge.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1)
+    }
+
+    /// The default DHCP range: every usable address after the gateway.
+    pub fn dhcp_range(&self) -> (Ipv4Addr, Ipv4Addr) {
+        let broadcast = u32::from(self.network) | !u32::from(self.netmask());
+        (
+            Ipv4Addr::from(u32::from(self.network) + 2),
+            Ipv4Addr::from(broadcast - 1),
+        )
+    }
+}
+
+pub fn parse_subnet(subnet: &str) -> Result<Subnet, String> {
+    let (address, prefix) = subnet
+        .split_once('/')
+        .ok_or_else(|| format!("invalid subnet '{}', expected <address>/<prefix>", subnet))?;
+    let address: Ipv4Addr = address
+        .parse()
+        .map_err(|_| format!("invalid IPv4 address '{}'", address))?;
+    let prefix: u8 = prefix
+        .parse()
+        .ok()
+        .filter(|p| (8..=30).contains(p))
+        .ok_or_else(|| format!("invalid prefix '{}', expected 8 to 30", prefix))?;
+
+    let netmask = u32::MAX << (32 - prefix);
+    Ok(Subnet {
+        network: Ipv4Addr::from(u32::from(address) & netmask),
+        prefix,
+    })
+}
+
+/// Build the XML for a network in `mode` (`nat`, `routed` or `isolated`).
+pub fn generate_network_xml(
+    name: &str,
+    mode: &str,
+    bridge: Option<String>,
+    subnet: &Subnet,
+    dhcp_range: (Ipv4Addr, Ipv4Addr),
+) -> String {
+    let forward = match mode {
+        "nat" => Some(types::Forward {
+            mode: "nat".to_string(),
+        }),
+        "routed" => Some(types::Forward {
+            mode: "route".to_string(),
+        }),
+        _ => None,
+    };
+
+    let network_config = types::NetworkConfig {
+        name: name.to_string(),
+        uuid: Some(Uuid::new_v4().to_string()),
+        forward,
+        bridge: Some(types::Bridge {
+            name: bridge,
+            stp: Some("on".to_string()),
+        }),
+        ip: vec![types::Ip {
+            address: subnet.gateway().to_string(),
+            netmask: Some(subnet.netmask().to_string()),
+            prefix: None,
+            dhcp: Some(types::Dhcp {
+                range: vec![types::DhcpRange {
+                    start: dhcp_range.0.to_string(),
+                    end: dhcp_range.1.to_string(),
+                }],
+                host: Vec::new(),
+            }),
+        }],
+    };
+
+    helpers::struct_to_xml(&network_config, "network")
+}
@@ -0,0 +1,176 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::{Value, json};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use virt::domain::Domain;
+
+/// Bytes moved per guest-file-read/guest-file-write call.
+const FILE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Output of a command run with guest-exec.
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Send a QMP command to the guest agent and return its `return` value.
+pub fn agent_command(domain: &Domain, command: &Value) -> Result<Value, String> {
+    let response = domain
+        .qemu_agent_command(
+            &command.to_string(),
+            virt_sys::VIR_DOMAIN_QEMU_AGENT_COMMAND_DEFAULT,
+            0,
+        )
+        .map_err(|e| format!("guest agent command failed: {}", e))?;
+    let mut response: Value = serde_json::from_str(&response)
+        .map_err(|e| format!("invalid guest agent response: {}", e))?;
+    Ok(response["return"].take())
+}
+
+fn decode(value: &Value) -> Vec<u8> {
+    value
+        .as_str()
+        .map(|s| STANDARD.decode(s).unwrap_or_default())
+        .unwrap_or_default()
+}
+
+/// Run `args` in the guest and wait up to `timeout` for it to finish.
+pub fn guest_exec(
+    domain: &Domain,
+    args: &[String],
+    timeout: Duration,
+) -> Result<ExecOutput, String> {
+    let Some((path, arg)) = args.split_first() else {
+        return Err("no command given".to_string());
+    };
+    let started = agent_command(
+        domain,
+        &json!({
+            "execute": "guest-exec",
+            "arguments": { "path": path, "arg": arg, "capture-output": true }
+        }),
+    )?;
+    let pid = started["pid"]
+        .as_i64()
+        .ok_or("guest-exec did not return a pid")?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let status = agent_command(
+            domain,
+            &json!({ "execute": "guest-exec-status", "arguments": { "pid": pid } }),
+        )?;
+        if status["exited"].as_bool() == Some(true) {
+            return Ok(ExecOutput {
+                exit_code: status["exitcode"].as_i64().unwrap_or(-1),
+                stdout: decode(&status["out-data"]),
+                stderr: decode(&status["err-data"]),
+            });
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("command did not finish within {:?}", timeout));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn file_open(domain: &Domain, path: &str, mode: &str) -> Result<i64, String> {
+    agent_command(
+        domain,
+        &json!({ "execute": "guest-file-open", "arguments": { "path": path, "mode": mode } }),
+    )?
+    .as_i64()
+    .ok_or_else(|| format!("could not open {} in the guest", path))
+}
+
+fn file_close(domain: &Domain, handle: i64) -> Result<(), String> {
+    agent_command(
+        domain,
+        &json!({ "execute": "guest-file-close", "arguments": { "handle": handle } }),
+    )?;
+    Ok(())
+}
+
+/// Copy the local file `local` to `remote` in the guest, returning the bytes written.
+pub fn upload(domain: &Domain, local: &str, remote: &str) -> Result<u64, String> {
+    let mut file =
+        std::fs::File::open(local).map_err(|e| format!("could not open {}: {}", local, e))?;
+    let handle = file_open(domain, remote, "wb")?;
+
+    let mut written = 0;
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+    let result = 'upload: loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break Ok(written),
+            Ok(n) => n,
+            Err(e) => break Err(format!("could not read {}: {}", local, e)),
+        };
+        // The guest may write less than it was sent, send the rest again
+        let mut chunk = &buf[..n];
+        while !chunk.is_empty() {
+            let reply = match agent_command(
+                domain,
+                &json!({
+                    "execute": "guest-file-write",
+                    "arguments": { "handle": handle, "buf-b64": STANDARD.encode(chunk) }
+                }),
+            ) {
+                Ok(reply) => reply,
+                Err(e) => break 'upload Err(e),
+            };
+            let count = reply["count"].as_u64().unwrap_or(0) as usize;
+            if count == 0 || count > chunk.len() {
+                break 'upload Err(format!(
+                    "guest wrote {} of {} bytes to {}",
+                    count,
+                    chunk.len(),
+                    remote
+                ));
+            }
+            chunk = &chunk[count..];
+            written += count as u64;
+        }
+    };
+
+    file_close(domain, handle)?;
+    result
+}
+
+/// Copy `remote` from the guest to the local file `local`, returning the bytes read.
+pub fn download(domain: &Domain, remote: &str, local: &str) -> Result<u64, String> {
+    let handle = file_open(domain, remote, "rb")?;
+    let mut file = match std::fs::File::create(local) {
+        Ok(file) => file,
+        Err(e) => {
+            file_close(domain, handle)?;
+            return Err(format!("could not create {}: {}", local, e));
+        }
+    };
+
+    let mut read = 0;
+    let result = loop {
+        let chunk = match agent_command(
+            domain,
+            &json!({
+                "execute": "guest-file-read",
+                "arguments": { "handle": handle, "count": FILE_CHUNK_SIZE }
+            }),
+        ) {
+            Ok(chunk) => chunk,
+            Err(e) => break Err(e),
+        };
+        let data = decode(&chunk["buf-b64"]);
+        if let Err(e) = file.write_all(&data) {
+            break Err(format!("could not write {}: {}", local, e));
+        }
+        read += data.len() as u64;
+        if chunk["eof"].as_bool() != Some(false) {
+            break Ok(read);
+        }
+    };
+
+    file_close(domain, handle)?;
+    result
+}
@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use virt::domain::Domain;
+use virt::stream::Stream;
+
+/// Ctrl+], the same detach key as `virsh console`.
+pub const ESCAPE_CHAR: u8 = 0x1d;
+
+/// Puts the local terminal in raw mode and restores it when dropped.
+pub(crate) struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /// Returns `None` when stdin is not a terminal.
+    pub(crate) fn enable() -> Option<RawTerminal> {
+        Self::set(|raw| unsafe { libc::cfmakeraw(raw) })
+    }
+
+    /// Only turn off line buffering and echo, so single key presses can be
+    /// read while output is still processed as usual.
+    pub(crate) fn keypresses() -> Option<RawTerminal> {
+        Self::set(|raw| raw.c_lflag &= !(libc::ICANON | libc::ECHO))
+    }
+
+    fn set(configure: impl FnOnce(&mut libc::termios)) -> Option<RawTerminal> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return None;
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            configure(&mut raw);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+            Some(RawTerminal { original })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Attach the local terminal to the serial console of `domain` until the user
+/// presses the escape character or the console closes. Console output is also
+/// written to `log` when given.
+pub fn attach(
+    domain: &Domain,
+    stream: Stream,
+    force: bool,
+    mut log: Option<File>,
+) -> Result<(), String> {
+    let flags = if force {
+        virt_sys::VIR_DOMAIN_CONSOLE_FORCE
+    } else {
+        virt_sys::VIR_DOMAIN_CONSOLE_SAFE
+    };
+    domain
+        .open_console(None, &stream, flags)
+        .map_err(|e| format!("could not open console: {}", e))?;
+
+    let _raw = RawTerminal::enable();
+    let detached = Arc::new(AtomicBool::new(false));
+
+    // Forward keyboard input from a separate thread, as stream reads block
+    let input_stream = stream.clone();
+    let input_detached = detached.clone();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0; 1024];
+        loop {
+            let n = match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Some(pos) = buf[..n].iter().position(|&b| b == ESCAPE_CHAR) {
+                let _ = input_stream.send(&buf[..pos]);
+                break;
+            }
+            if input_stream.send(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        input_detached.store(true, Ordering::SeqCst);
+        // Unblocks the reader below
+        let _ = input_stream.abort();
+    });
+
+    let mut stdout = std::io::stdout();
+    let mut buf = [0; 4096];
+    loop {
+        let n = match stream.recv(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(_) if detached.load(Ordering::SeqCst) => break,
+            Err(e) => return Err(format!("console stream failed: {}", e)),
+        };
+        stdout.write_all(&buf[..n]).unwrap();
+        stdout.flush().unwrap();
+        if let Some(log) = log.as_mut() {
+            log.write_all(&buf[..n])
+                .map_err(|e| format!("could not write console log: {}", e))?;
+        }
+    }
+    Ok(())
+}
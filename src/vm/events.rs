@@ -0,0 +1,368 @@
+use serde::Serialize;
+use std::ffi::{CStr, c_char, c_int, c_void};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use virt::connect::Connect;
+use virt::domain::Domain;
+use virt_sys::{virConnectPtr, virDomainPtr};
+
+/// An event reported by libvirt for a domain.
+#[derive(Serialize, Clone, Debug)]
+pub struct DomainEvent {
+    pub domain: String,
+    /// RFC 3339 time the event was received
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The domain was defined, started, stopped, paused, ...
+    Lifecycle { state: String, detail: String },
+    /// The guest rebooted
+    Reboot,
+    /// The watchdog device fired
+    Watchdog { action: String },
+    /// A disk failed
+    IoError {
+        source: String,
+        device: String,
+        action: String,
+        reason: String,
+    },
+    /// The guest agent connected or disconnected
+    AgentLifecycle { state: String, reason: String },
+}
+
+impl std::fmt::Display for DomainEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ", self.timestamp, self.domain)?;
+        match &self.kind {
+            EventKind::Lifecycle { state, detail } => write!(f, "{} ({})", state, detail),
+            EventKind::Reboot => write!(f, "rebooted"),
+            EventKind::Watchdog { action } => write!(f, "watchdog fired, action {}", action),
+            EventKind::IoError {
+                source,
+                device,
+                action,
+                reason,
+            } => write!(
+                f,
+                "I/O error on {} ({}): {}, action {}",
+                device, source, reason, action
+            ),
+            EventKind::AgentLifecycle { state, reason } => {
+                write!(f, "guest agent {} ({})", state, reason)
+            }
+        }
+    }
+}
+
+/// Async stream of domain events, ending when the libvirt connection is lost.
+pub struct EventStream {
+    receiver: UnboundedReceiver<DomainEvent>,
+}
+
+impl EventStream {
+    /// Wait for the next event.
+    pub async fn next(&mut self) -> Option<DomainEvent> {
+        self.receiver.recv().await
+    }
+}
+
+impl futures_core::Stream for EventStream {
+    type Item = DomainEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DomainEvent>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+fn pick(names: &[&'static str], index: c_int) -> &'static str {
+    usize::try_from(index)
+        .ok()
+        .and_then(|i| names.get(i))
+        .unwrap_or(&"unknown")
+}
+
+fn lifecycle_names(event: c_int, detail: c_int) -> (&'static str, &'static str) {
+    let (state, details): (&str, &[&str]) = match event as u32 {
+        virt_sys::VIR_DOMAIN_EVENT_DEFINED => {
+            ("defined", &["added", "updated", "renamed", "from snapshot"])
+        }
+        virt_sys::VIR_DOMAIN_EVENT_UNDEFINED => ("undefined", &["removed", "renamed"]),
+        virt_sys::VIR_DOMAIN_EVENT_STARTED => (
+            "started",
+            &[
+                "booted",
+                "migrated",
+                "restored",
+                "from snapshot",
+                "woken up",
+            ],
+        ),
+        virt_sys::VIR_DOMAIN_EVENT_SUSPENDED => (
+            "suspended",
+            &[
+                "paused",
+                "migrated",
+                "I/O error",
+                "watchdog",
+                "restored",
+                "from snapshot",
+                "API error",
+                "post-copy",
+                "post-copy failed",
+            ],
+        ),
+        virt_sys::VIR_DOMAIN_EVENT_RESUMED => (
+            "resumed",
+            &[
+                "unpaused",
+                "migrated",
+                "from snapshot",
+                "post-copy",
+                "post-copy failed",
+            ],
+        ),
+        virt_sys::VIR_DOMAIN_EVENT_STOPPED => (
+            "stopped",
+            &[
+                "shutdown",
+                "destroyed",
+                "crashed",
+                "migrated",
+                "saved",
+                "failed",
+                "from snapshot",
+            ],
+        ),
+        virt_sys::VIR_DOMAIN_EVENT_SHUTDOWN => ("shutdown", &["finished", "guest", "host"]),
+        virt_sys::VIR_DOMAIN_EVENT_PMSUSPENDED => ("pmsuspended", &["memory", "disk"]),
+        virt_sys::VIR_DOMAIN_EVENT_CRASHED => ("crashed", &["panicked", "crashloaded"]),
+        _ => ("unknown", &[]),
+    };
+    (state, pick(details, detail))
+}
+
+fn string_from_ptr(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Hand an event for `dom` to the sender passed as callback `opaque`.
+unsafe fn send(dom: virDomainPtr, opaque: *mut c_void, kind: EventKind) {
+    let sender = unsafe { &*(opaque as *const UnboundedSender<DomainEvent>) };
+    let _ = sender.send(DomainEvent {
+        domain: string_from_ptr(unsafe { virt_sys::virDomainGetName(dom) }),
+        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        kind,
+    });
+}
+
+unsafe extern "C" fn lifecycle_callback(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    event: c_int,
+    detail: c_int,
+    opaque: *mut c_void,
+) -> c_int {
+    let (state, detail) = lifecycle_names(event, detail);
+    let kind = EventKind::Lifecycle {
+        state: state.to_string(),
+        detail: detail.to_string(),
+    };
+    unsafe { send(dom, opaque, kind) };
+    0
+}
+
+unsafe extern "C" fn reboot_callback(_conn: virConnectPtr, dom: virDomainPtr, opaque: *mut c_void) {
+    unsafe { send(dom, opaque, EventKind::Reboot) };
+}
+
+unsafe extern "C" fn watchdog_callback(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    action: c_int,
+    opaque: *mut c_void,
+) {
+    let actions = [
+        "none",
+        "pause",
+        "reset",
+        "poweroff",
+        "shutdown",
+        "debug",
+        "inject-nmi",
+    ];
+    let kind = EventKind::Watchdog {
+        action: pick(&actions, action).to_string(),
+    };
+    unsafe { send(dom, opaque, kind) };
+}
+
+unsafe extern "C" fn io_error_callback(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    src_path: *const c_char,
+    dev_alias: *const c_char,
+    action: c_int,
+    reason: *const c_char,
+    opaque: *mut c_void,
+) {
+    let kind = EventKind::IoError {
+        source: string_from_ptr(src_path),
+        device: string_from_ptr(dev_alias),
+        action: pick(&["none", "pause", "report"], action).to_string(),
+        reason: string_from_ptr(reason),
+    };
+    unsafe { send(dom, opaque, kind) };
+}
+
+unsafe extern "C" fn agent_lifecycle_callback(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    state: c_int,
+    reason: c_int,
+    opaque: *mut c_void,
+) {
+    let kind = EventKind::AgentLifecycle {
+        state: pick(&["unknown", "connected", "disconnected"], state).to_string(),
+        reason: pick(&["unknown", "domain started", "channel event"], reason).to_string(),
+    };
+    unsafe { send(dom, opaque, kind) };
+}
+
+unsafe extern "C" fn free_sender(opaque: *mut c_void) {
+    drop(unsafe { Box::from_raw(opaque as *mut UnboundedSender<DomainEvent>) });
+}
+
+/// Register `callback` for `event_id`, which libvirt casts back to the
+/// signature matching the event.
+fn register(
+    conn: &Connect,
+    domain: Option<&Domain>,
+    event_id: u32,
+    callback: *const (),
+    sender: &UnboundedSender<DomainEvent>,
+) -> Result<c_int, String> {
+    let opaque = Box::into_raw(Box::new(sender.clone())) as *mut c_void;
+    let id = unsafe {
+        let callback: unsafe extern "C" fn(virConnectPtr, virDomainPtr, *mut c_void) =
+            std::mem::transmute(callback);
+        virt_sys::virConnectDomainEventRegisterAny(
+            conn.as_ptr(),
+            domain.map_or(std::ptr::null_mut(), |d| d.as_ptr()),
+            event_id as c_int,
+            Some(callback),
+            opaque,
+            Some(free_sender),
+        )
+    };
+    if id < 0 {
+        unsafe { free_sender(opaque) };
+        return Err(format!(
+            "could not register for domain events: {}",
+            virt::error::Error::last_error()
+        ));
+    }
+    Ok(id)
+}
+
+/// Register the callbacks and dispatch events until the stream is dropped or
+/// the connection fails. `ready` gets the outcome of the registration.
+fn run_event_loop(
+    name: Option<&str>,
+    sender: UnboundedSender<DomainEvent>,
+    ready: std::sync::mpsc::Sender<Result<(), String>>,
+) {
+    // The event loop implementation has to be in place before connecting
+    static REGISTER_EVENT_IMPL: Once = Once::new();
+    REGISTER_EVENT_IMPL.call_once(|| virt::event::event_register_default_impl().unwrap());
+
+    let conn = match Connect::open(Some("qemu:///system")) {
+        Ok(conn) => conn,
+        Err(e) => {
+            let _ = ready.send(Err(format!("could not connect to libvirt: {}", e)));
+            return;
+        }
+    };
+    let domain = match name.map(|name| Domain::lookup_by_name(&conn, name)) {
+        Some(Err(e)) => {
+            let _ = ready.send(Err(format!("could not find domain: {}", e)));
+            return;
+        }
+        Some(Ok(domain)) => Some(domain),
+        None => None,
+    };
+    // Wake the loop up regularly to notice a dropped stream or a dead daemon
+    let _ = conn.set_keep_alive(5, 3);
+
+    let callbacks: [(u32, *const ()); 5] = [
+        (
+            virt_sys::VIR_DOMAIN_EVENT_ID_LIFECYCLE,
+            lifecycle_callback as *const (),
+        ),
+        (
+            virt_sys::VIR_DOMAIN_EVENT_ID_REBOOT,
+            reboot_callback as *const (),
+        ),
+        (
+            virt_sys::VIR_DOMAIN_EVENT_ID_WATCHDOG,
+            watchdog_callback as *const (),
+        ),
+        (
+            virt_sys::VIR_DOMAIN_EVENT_ID_IO_ERROR_REASON,
+            io_error_callback as *const (),
+        ),
+        (
+            virt_sys::VIR_DOMAIN_EVENT_ID_AGENT_LIFECYCLE,
+            agent_lifecycle_callback as *const (),
+        ),
+    ];
+    let mut callback_ids = Vec::new();
+    for (event_id, callback) in callbacks {
+        match register(&conn, domain.as_ref(), event_id, callback, &sender) {
+            Ok(id) => callback_ids.push(id),
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        }
+    }
+    let _ = ready.send(Ok(()));
+
+    while !sender.is_closed() && conn.is_alive().unwrap_or(false) {
+        if virt::event::event_run_default_impl().is_err() {
+            break;
+        }
+    }
+
+    for id in callback_ids {
+        unsafe { virt_sys::virConnectDomainEventDeregisterAny(conn.as_ptr(), id) };
+    }
+}
+
+/// Subscribe to the events of one domain, or of all domains when `name` is
+/// `None`. Events are dispatched from a background thread running the libvirt
+/// event loop.
+pub fn subscribe(name: Option<&str>) -> Result<EventStream, String> {
+    let (sender, receiver) = unbounded_channel();
+    let (ready_sender, ready) = std::sync::mpsc::channel();
+    let name = name.map(str::to_string);
+    std::thread::spawn(move || run_event_loop(name.as_deref(), sender, ready_sender));
+
+    ready
+        .recv()
+        .map_err(|_| "event loop exited unexpectedly".to_string())??;
+    Ok(EventStream { receiver })
+}
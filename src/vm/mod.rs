@@ -1,30 +1,573 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 use virt::connect::Connect;
 use virt::domain::Domain;
+use virt::stream::Stream;
 
-//use crate::helpers;
+use crate::helpers;
+use crate::metrics;
+use crate::network::ipam;
 
+pub mod agent;
+pub mod console;
+pub mod events;
+pub mod snapshot;
+pub mod startup;
+pub mod stats;
+pub mod template;
 pub mod types;
 pub mod utils;
 
-pub fn create_vm(
+/// Settings for a new VM, as collected from the `create` command.
+pub struct CreateVmOptions {
+    pub name: String,
+    pub username: String,
+    pub password: String,
+    /// Memory in MiB
+    pub memory: u64,
+    pub vcpus: u8,
+    /// Memory in MiB the VM can be grown to while running
+    pub max_memory: Option<u64>,
+    /// vCPUs the VM can be grown to while running
+    pub max_vcpus: Option<u8>,
+    /// Boot the VM when the host boots
+    pub autostart: bool,
+    /// Disk size in GB
+    pub disk_size: u64,
+    /// Template or cloud image the root disk is backed by
+    pub image: String,
+    pub iotune: Option<types::Iotune>,
+    /// NICs to attach, a single bridge on virbr0 when empty
+    pub interfaces: Vec<types::Interface>,
+    /// Wait up to this many seconds for the guest to report an address
+    pub wait_timeout: Option<u64>,
+    /// Private SSH key to authorize, vm-alloc's own key when not set
+    pub ssh_key: Option<String>,
+    pub tags: Vec<types::Tag>,
+    /// Add a guest agent channel and install qemu-guest-agent in the guest
+    pub guest_agent: bool,
+    /// `none`, `vnc` or `spice`
+    pub graphics: String,
+    pub graphics_listen: Option<String>,
+    pub graphics_password: Option<String>,
+}
+
+/// Reserve DHCP addresses for the interfaces on IPAM-managed networks, giving
+/// them deterministic MACs where they have none. Returns the `(network, mac)`
+/// pairs reserved; on failure the ones reserved so far are released again.
+fn reserve_network_addresses(
+    conn: &Connect,
     name: &str,
-    username: &str,
-    password: &str,
-    memory: u64,
-    vcpus: u8,
-    disk_size: u64,
-) {
-    let seed_iso_path = utils::create_seed_iso(name, username, password);
-    let disk_path = utils::create_qemu_img_disk(name, disk_size);
-
-    let domain_xml =
-        utils::generate_installation_domain_xml(name, memory, vcpus, disk_path, seed_iso_path);
+    interfaces: &mut [types::Interface],
+) -> Result<Vec<(String, String)>, String> {
+    let mut reserved = Vec::new();
+    for (index, interface) in interfaces.iter_mut().enumerate() {
+        let Some(network_name) = interface.source.as_ref().and_then(|s| s.network.clone()) else {
+            continue;
+        };
+        let mac = interface
+            .mac
+            .get_or_insert_with(|| types::Mac {
+                address: ipam::deterministic_mac(name, &network_name, index),
+            })
+            .address
+            .clone();
+        match ipam::reserve_address(conn, &network_name, name, &mac) {
+            Ok(Some(address)) => {
+                println!(
+                    "Reserved {} for {} on network {}",
+                    address, mac, network_name
+                );
+                reserved.push((network_name, mac));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                release_network_addresses(conn, &reserved);
+                return Err(format!("could not reserve an address: {}", e));
+            }
+        }
+    }
+    Ok(reserved)
+}
+
+/// Release reservations made by `reserve_network_addresses`.
+fn release_network_addresses(conn: &Connect, reserved: &[(String, String)]) {
+    for (network_name, mac) in reserved {
+        ipam::release_address(conn, network_name, mac);
+    }
+}
+
+fn read_public_key(ssh_key: &str) -> String {
+    match std::fs::read_to_string(format!("{}.pub", ssh_key)) {
+        Ok(key) => key.trim().to_string(),
+        Err(e) => {
+            eprintln!("Error reading public key {}.pub: {}", ssh_key, e);
+            std::process::exit(1);
+        }
+    }
+}
 
+pub fn create_vm(mut options: CreateVmOptions) {
+    let started = Instant::now();
+    let name = options.name.clone();
     let mut conn = Connect::open(Some("qemu:///system")).unwrap();
 
-    let domain = Domain::define_xml(&mut conn, &domain_xml).unwrap();
+    if Domain::lookup_by_name(&conn, &name).is_ok() {
+        eprintln!("A VM named {} already exists.", name);
+        std::process::exit(1);
+    }
 
-    domain.create().unwrap();
+    if options.max_memory.is_some_and(|max| max < options.memory)
+        || options.max_vcpus.is_some_and(|max| max < options.vcpus)
+    {
+        eprintln!("The maximum memory and vCPUs cannot be below the initial ones.");
+        std::process::exit(1);
+    }
+
+    let Some(image_path) = utils::resolve_image(&options.image) else {
+        eprintln!(
+            "No template or cloud image named {} found in {} or /var/lib/libvirt/images/iso.",
+            options.image,
+            utils::TEMPLATE_DIR
+        );
+        std::process::exit(1);
+    };
+
+    let ssh_key = options
+        .ssh_key
+        .clone()
+        .unwrap_or_else(utils::ensure_ssh_key);
+    let public_key = read_public_key(&ssh_key);
+
+    // From here on, failures undo the reservations and files made so far
+    let mut cleanup = Cleanup::default();
+    match reserve_network_addresses(&conn, &name, &mut options.interfaces) {
+        Ok(reserved) => cleanup.reservations = reserved,
+        Err(e) => cleanup.fail(&conn, &format!("Error reserving network addresses: {}", e)),
+    }
+
+    utils::create_log_dir(&name);
+
+    let seed_iso_path = match utils::create_seed_iso(
+        &name,
+        &options.username,
+        &options.password,
+        vec![public_key],
+        options.guest_agent,
+    ) {
+        Ok(path) => path,
+        Err(e) => cleanup.fail(&conn, &format!("Error creating seed ISO: {}", e)),
+    };
+    cleanup.files.push(seed_iso_path.clone());
+    let disk_path = match utils::create_qemu_img_disk(&name, options.disk_size, &image_path) {
+        Ok(path) => path,
+        Err(e) => cleanup.fail(&conn, &format!("Error creating disk image: {}", e)),
+    };
+    cleanup.files.push(disk_path.clone());
+
+    let domain_xml = utils::generate_installation_domain_xml(&options, disk_path, seed_iso_path);
+
+    let domain = match Domain::define_xml(&mut conn, &domain_xml) {
+        Ok(domain) => domain,
+        Err(e) => cleanup.fail(&conn, &format!("Error defining {}: {}", name, e)),
+    };
+    cleanup.domain = Some(domain.clone());
+
+    let metadata = types::VmMetadata {
+        username: options.username.clone(),
+        ssh_key: Some(ssh_key),
+        tags: options.tags.clone(),
+        created_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+        image: Some(options.image.clone()),
+        startup: None,
+    };
+    if let Err(e) = utils::set_vm_metadata(&domain, &metadata) {
+        cleanup.fail(
+            &conn,
+            &format!("Error setting the metadata of {}: {}", name, e),
+        );
+    }
+    if options.autostart
+        && let Err(e) = domain.set_autostart(true)
+    {
+        cleanup.fail(&conn, &format!("Error setting autostart: {}", e));
+    }
+    utils::forget_host_key(&name);
+
+    if let Err(e) = domain.create() {
+        cleanup.fail(&conn, &format!("Error booting VM {}: {}", name, e));
+    }
+    metrics::record_vm_created(started.elapsed());
+
+    if let Some(timeout) = options.wait_timeout {
+        println!("Waiting for {} to report an IP address...", name);
+        print_guest_addresses(&name, &domain, timeout);
+    }
+}
+
+/// What a create or clone has made so far, removed again when it fails.
+#[derive(Default)]
+struct Cleanup<'a> {
+    /// Source VM and the snapshot that froze its disks for a linked clone
+    frozen: Option<(&'a Domain, String)>,
+    files: Vec<String>,
+    reservations: Vec<(String, String)>,
+    domain: Option<Domain>,
+}
+
+impl Cleanup<'_> {
+    /// Report `message`, undo everything so far and exit.
+    fn fail(&self, conn: &Connect, message: &str) -> ! {
+        eprintln!("{}", message);
+        if let Some(domain) = &self.domain {
+            let _ = domain.undefine();
+        }
+        for file in &self.files {
+            let _ = std::fs::remove_file(file);
+        }
+        release_network_addresses(conn, &self.reservations);
+        if let Some((source, snapshot)) = &self.frozen
+            && let Err(e) = snapshot::unfreeze_disks(source, snapshot)
+        {
+            eprintln!("Error restoring the disks of the source VM: {}", e);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Create `destination` as a copy of the stopped VM `source`, with a new UUID,
+/// new MACs and a new seed ISO so cloud-init sets it up under its own name.
+/// Linked clones put qcow2 overlays on the source disks, which are frozen
+/// first with a `clone-<destination>` snapshot so neither VM writes to them.
+/// The source definition is edited as XML so nothing it holds is lost.
+pub fn clone_vm(source: &str, destination: &str, linked: bool, password: &str, start: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, source).unwrap();
+
+    if Domain::lookup_by_name(&conn, destination).is_ok() {
+        eprintln!("A VM named {} already exists.", destination);
+        std::process::exit(1);
+    }
+    if domain.is_active().unwrap() {
+        eprintln!("VM {} is running, shut it down before cloning it.", source);
+        std::process::exit(1);
+    }
+    let Some(metadata) = utils::get_vm_metadata(&domain) else {
+        eprintln!(
+            "VM {} was not created by vm-alloc and cannot be cloned.",
+            source
+        );
+        std::process::exit(1);
+    };
+
+    println!("Cloning VM {} to {}", source, destination);
+    // Secure XML keeps the display password
+    let source_xml = domain
+        .get_xml_desc(virt_sys::VIR_DOMAIN_XML_INACTIVE | virt_sys::VIR_DOMAIN_XML_SECURE)
+        .unwrap();
+    let domain_config: types::DomainConfig = helpers::xml_to_struct(&source_xml);
+    let Some(mut devices) = domain_config.devices else {
+        eprintln!("VM {} has no devices to clone.", source);
+        std::process::exit(1);
+    };
+    let mut clone_xml = helpers::parse_xml(&source_xml);
+
+    let mut cleanup = Cleanup::default();
+    if linked {
+        let snapshot_name = format!("clone-{}", destination);
+        if let Err(e) = snapshot::freeze_disks(&domain, &snapshot_name) {
+            eprintln!("Error freezing the disks of {}: {}", source, e);
+            std::process::exit(1);
+        }
+        cleanup.frozen = Some((&domain, snapshot_name));
+    }
+
+    let guest_agent = devices
+        .channel
+        .iter()
+        .any(|c| c.target.name.as_deref() == Some("org.qemu.guest_agent.0"));
+    let ssh_key = metadata
+        .ssh_key
+        .clone()
+        .unwrap_or_else(utils::ensure_ssh_key);
+    let seed_iso_path = match utils::create_seed_iso(
+        destination,
+        &metadata.username,
+        password,
+        vec![read_public_key(&ssh_key)],
+        guest_agent,
+    ) {
+        Ok(path) => path,
+        Err(e) => cleanup.fail(&conn, &format!("Error creating seed ISO: {}", e)),
+    };
+    cleanup.files.push(seed_iso_path.clone());
+
+    // Let libvirt pick new MACs, except on networks where IPAM assigns them
+    for interface in devices.interface.iter_mut() {
+        interface.mac = None;
+    }
+    match reserve_network_addresses(&conn, destination, &mut devices.interface) {
+        Ok(reserved) => cleanup.reservations = reserved,
+        Err(e) => cleanup.fail(&conn, &format!("Error reserving network addresses: {}", e)),
+    }
+
+    helpers::set_child_text(&mut clone_xml, "name", destination);
+    helpers::set_child_text(&mut clone_xml, "uuid", &uuid::Uuid::new_v4().to_string());
+    let devices_xml = clone_xml.get_mut_child("devices").unwrap();
+
+    let image_dir = "/var/lib/libvirt/images";
+    let mut first_disk = true;
+    for disk in helpers::children_mut(devices_xml, "disk") {
+        let device = disk.attributes.get("device").cloned().unwrap_or_default();
+        let target = disk
+            .get_child("target")
+            .and_then(|t| t.attributes.get("dev"))
+            .cloned();
+        let (Some(source), Some(target)) = (disk.get_mut_child("source"), target) else {
+            continue;
+        };
+        let Some(file) = source.attributes.get("file").cloned() else {
+            continue;
+        };
+        match device.as_str() {
+            "disk" => {
+                let path = if first_disk {
+                    format!("{}/{}.qcow2", image_dir, destination)
+                } else {
+                    format!("{}/{}-{}.qcow2", image_dir, destination, target)
+                };
+                cleanup.files.push(path.clone());
+                if let Err(e) = utils::clone_disk(&file, &path, linked) {
+                    cleanup.fail(&conn, &format!("Error cloning disk {}: {}", target, e));
+                }
+                source.attributes.insert("file".to_string(), path);
+                first_disk = false;
+            }
+            "cdrom" => {
+                source
+                    .attributes
+                    .insert("file".to_string(), seed_iso_path.clone());
+            }
+            _ => continue,
+        }
+        // libvirt detects the backing chain of the new image itself
+        disk.children
+            .retain(|c| c.as_element().is_none_or(|e| e.name != "backingStore"));
+    }
+
+    for (interface, config) in
+        helpers::children_mut(devices_xml, "interface").zip(&devices.interface)
+    {
+        interface
+            .children
+            .retain(|c| c.as_element().is_none_or(|e| e.name != "mac"));
+        if let Some(mac) = &config.mac {
+            let mut mac_xml = xmltree::Element::new("mac");
+            mac_xml
+                .attributes
+                .insert("address".to_string(), mac.address.clone());
+            interface.children.push(xmltree::XMLNode::Element(mac_xml));
+        }
+    }
+
+    for serial in helpers::children_mut(devices_xml, "serial") {
+        if let Some(log) = serial.get_mut_child("log") {
+            log.attributes
+                .insert("file".to_string(), utils::serial_log_path(destination));
+        }
+    }
+
+    let clone = match Domain::define_xml(&conn, &helpers::element_to_xml(&clone_xml)) {
+        Ok(clone) => clone,
+        Err(e) => cleanup.fail(&conn, &format!("Error defining {}: {}", destination, e)),
+    };
+    cleanup.domain = Some(clone.clone());
+    let clone_metadata = types::VmMetadata {
+        created_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+        // Booting with the host is turned on per VM, not inherited
+        startup: None,
+        ..metadata
+    };
+    if let Err(e) = utils::set_vm_metadata(&clone, &clone_metadata) {
+        cleanup.fail(
+            &conn,
+            &format!("Error setting the metadata of {}: {}", destination, e),
+        );
+    }
+    utils::create_log_dir(destination);
+    utils::forget_host_key(destination);
+
+    if start {
+        println!("Booting VM: {}", destination);
+        if let Err(e) = clone.create() {
+            eprintln!("Error booting VM {}: {}", destination, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Undo the file moves and remove the files created by a failed rename.
+fn undo_rename_files(moved: &[(String, String)], created: &[String]) {
+    for (from, to) in moved.iter().rev() {
+        if let Err(e) = std::fs::rename(to, from) {
+            eprintln!("Could not move {} back to {}: {}", to, from, e);
+        }
+    }
+    for file in created {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+/// Rename a stopped VM, along with the disk images and seed ISO named after
+/// it. The new seed ISO carries the new hostname. Everything is put back when
+/// a step fails.
+pub fn rename_vm(old: &str, new: &str) {
+    if new.is_empty() || new.contains('/') {
+        eprintln!("Invalid VM name '{}'.", new);
+        std::process::exit(1);
+    }
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, old).unwrap();
+
+    if Domain::lookup_by_name(&conn, new).is_ok() {
+        eprintln!("A VM named {} already exists.", new);
+        std::process::exit(1);
+    }
+    if domain.is_active().unwrap() {
+        eprintln!("VM {} is running, shut it down before renaming it.", old);
+        std::process::exit(1);
+    }
+    if domain.has_managed_save(0).unwrap() {
+        eprintln!(
+            "VM {} has a saved state, restore and shut it down before renaming it.",
+            old
+        );
+        std::process::exit(1);
+    }
+    if !domain.list_all_snapshots(0).unwrap().is_empty() {
+        eprintln!(
+            "VM {} has snapshots, which libvirt cannot carry over to a new name.",
+            old
+        );
+        std::process::exit(1);
+    }
+
+    println!("Renaming VM {} to {}", old, new);
+    let old_xml = domain
+        .get_xml_desc(virt_sys::VIR_DOMAIN_XML_INACTIVE | virt_sys::VIR_DOMAIN_XML_SECURE)
+        .unwrap();
+    let domain_config: types::DomainConfig = helpers::xml_to_struct(&old_xml);
+    // Only file paths change, so edit the XML to keep everything else as is
+    let mut renamed_xml = helpers::parse_xml(&old_xml);
+    let devices_xml = renamed_xml.get_mut_child("devices").unwrap();
+
+    // (old path, new path) of moved images, and the new seed ISOs
+    let mut moved: Vec<(String, String)> = Vec::new();
+    let mut created: Vec<String> = Vec::new();
+    let mut old_seeds: Vec<String> = Vec::new();
+    for disk in helpers::children_mut(devices_xml, "disk") {
+        let device = disk.attributes.get("device").cloned().unwrap_or_default();
+        let Some(source) = disk.get_mut_child("source") else {
+            continue;
+        };
+        let Some(file) = source.attributes.get("file").cloned() else {
+            continue;
+        };
+        let path = Path::new(&file);
+        let file_name = path.file_name().unwrap().to_string_lossy();
+
+        if device == "cdrom" && file_name == format!("{}-seed.iso", old) {
+            match utils::rename_seed_iso(&file, new) {
+                Ok(seed) => {
+                    source.attributes.insert("file".to_string(), seed.clone());
+                    created.push(seed);
+                    old_seeds.push(file);
+                }
+                Err(e) => {
+                    eprintln!("Error writing the seed ISO of {}: {}", new, e);
+                    undo_rename_files(&moved, &created);
+                    std::process::exit(1);
+                }
+            }
+        } else if device == "disk"
+            && (file_name == format!("{}.qcow2", old)
+                || file_name.starts_with(&format!("{}-", old)))
+        {
+            let renamed = path
+                .with_file_name(file_name.replacen(old, new, 1))
+                .display()
+                .to_string();
+            let result = if Path::new(&renamed).exists() {
+                Err(format!("{} already exists", renamed))
+            } else {
+                std::fs::rename(&file, &renamed).map_err(|e| e.to_string())
+            };
+            if let Err(e) = result {
+                eprintln!("Error moving {}: {}", file, e);
+                undo_rename_files(&moved, &created);
+                std::process::exit(1);
+            }
+            println!("Moved {} to {}", file, renamed);
+            source
+                .attributes
+                .insert("file".to_string(), renamed.clone());
+            moved.push((file, renamed));
+        }
+    }
+    for serial in helpers::children_mut(devices_xml, "serial") {
+        if let Some(log) = serial.get_mut_child("log") {
+            log.attributes
+                .insert("file".to_string(), utils::serial_log_path(new));
+        }
+    }
+
+    // Point the definition at the moved files first; libvirt keeps the
+    // definition when renaming
+    if let Err(e) = Domain::define_xml(&conn, &helpers::element_to_xml(&renamed_xml)) {
+        eprintln!("Error updating the disks of {}: {}", old, e);
+        undo_rename_files(&moved, &created);
+        std::process::exit(1);
+    }
+    if let Err(e) = domain.rename(new, 0) {
+        eprintln!("Error renaming {}: {}", old, e);
+        if let Err(e) = Domain::define_xml(&conn, &old_xml) {
+            eprintln!("Could not restore the definition of {}: {}", old, e);
+        }
+        undo_rename_files(&moved, &created);
+        std::process::exit(1);
+    }
+
+    // The VM is renamed, what follows is tidying up and only warns on failure
+    for seed in old_seeds {
+        let _ = std::fs::remove_file(seed);
+    }
+    if std::fs::rename(
+        format!("{}/{}", utils::LOG_DIR, old),
+        format!("{}/{}", utils::LOG_DIR, new),
+    )
+    .is_err()
+        && let Err(e) = std::fs::create_dir_all(format!("{}/{}", utils::LOG_DIR, new))
+    {
+        eprintln!(
+            "Warning: could not create the log directory of {}: {}",
+            new, e
+        );
+    }
+    for interface in domain_config.devices.iter().flat_map(|d| &d.interface) {
+        let (Some(network_name), Some(mac)) = (
+            interface.source.as_ref().and_then(|s| s.network.as_ref()),
+            interface.mac.as_ref(),
+        ) else {
+            continue;
+        };
+        if let Err(e) = ipam::rename_reservation(&conn, network_name, &mac.address, new) {
+            eprintln!("Warning: {}", e);
+        }
+    }
+    utils::forget_host_key(old);
+    println!("Renamed VM {} to {}", old, new);
 }
 
 pub fn boot_vm(name: &str) {
@@ -43,50 +586,217 @@ pub fn delete_vm(name: &str) {
         domain.destroy().unwrap();
     }
 
-    domain.undefine().unwrap();
+    let domain_config: types::DomainConfig = helpers::xml_to_struct(
+        &domain
+            .get_xml_desc(virt_sys::VIR_DOMAIN_XML_INACTIVE)
+            .unwrap(),
+    );
+    for interface in domain_config.devices.into_iter().flat_map(|d| d.interface) {
+        if let (Some(source), Some(mac)) = (interface.source, interface.mac)
+            && let Some(network_name) = source.network
+        {
+            ipam::release_address(&conn, &network_name, &mac.address);
+        }
+    }
+
+    // Saved state and snapshot metadata have to go along with the VM
+    domain
+        .undefine_flags(
+            virt_sys::VIR_DOMAIN_UNDEFINE_MANAGED_SAVE
+                | virt_sys::VIR_DOMAIN_UNDEFINE_SNAPSHOTS_METADATA,
+        )
+        .unwrap();
+    utils::forget_host_key(name);
+    metrics::record_vm_deleted();
 }
 
-pub fn list_vms() {
-    println!("Listing all VMs");
+/// Options of `list`
+pub struct ListOptions {
+    /// `table`, `json`, `yaml` or `csv`
+    pub output: String,
+    /// Only show `running`, `paused`, `saved` or `stopped` VMs
+    pub state: Option<String>,
+    /// Only show VMs carrying all of these tags
+    pub filters: Vec<types::Tag>,
+    /// `name`, `state`, `vcpus`, `memory` or `age`
+    pub sort: String,
+    pub no_headers: bool,
+}
+
+pub fn list_vms(options: &ListOptions) {
     let conn = Connect::open(Some("qemu:///system")).unwrap();
     let domains = conn.list_all_domains(0).unwrap();
-    for domain in domains {
-        let name = domain.get_name().unwrap();
-        let id = domain.get_id().unwrap_or(0); // 0 means inactive
-        let is_active = domain.is_active().unwrap();
-        println!(
-            "Name: {}, ID: {}, Active: {}",
-            name,
-            if id == 0 {
-                "N/A".to_string()
-            } else {
-                id.to_string()
-            },
-            is_active
-        );
+    let mut vms: Vec<types::VmSummary> = domains
+        .iter()
+        .filter(|domain| {
+            let state = domain.get_state().unwrap().0;
+            match options.state.as_deref() {
+                Some("running") => {
+                    domain.is_active().unwrap() && state != virt_sys::VIR_DOMAIN_PAUSED
+                }
+                Some("paused") => state == virt_sys::VIR_DOMAIN_PAUSED,
+                Some("saved") => utils::display_state(domain, state) == "saved",
+                Some("stopped") => utils::display_state(domain, state) == "shut off",
+                _ => true,
+            }
+        })
+        .filter(|domain| {
+            let tags = utils::get_vm_metadata(domain)
+                .map(|m| m.tags)
+                .unwrap_or_default();
+            options.filters.iter().all(|f| utils::tags_match(&tags, f))
+        })
+        .map(utils::collect_vm_summary)
+        .collect();
+
+    match options.sort.as_str() {
+        "state" => vms.sort_by(|a, b| a.state.cmp(&b.state).then(a.name.cmp(&b.name))),
+        "vcpus" => vms.sort_by(|a, b| b.vcpus.cmp(&a.vcpus).then(a.name.cmp(&b.name))),
+        "memory" => vms.sort_by(|a, b| b.memory_kib.cmp(&a.memory_kib).then(a.name.cmp(&b.name))),
+        // Youngest first, VMs without a creation time last
+        "age" => vms.sort_by_key(|vm| (vm.age_seconds.is_none(), vm.age_seconds)),
+        _ => vms.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+
+    match options.output.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&vms).unwrap()),
+        "yaml" => print!("{}", serde_yml::to_string(&vms).unwrap()),
+        "csv" => {
+            let rows: Vec<Vec<String>> = vms
+                .iter()
+                .map(|vm| {
+                    vec![
+                        vm.name.clone(),
+                        vm.state.clone(),
+                        vm.vcpus.to_string(),
+                        vm.memory_kib.to_string(),
+                        vm.addresses.join(" "),
+                        vm.image.clone().unwrap_or_default(),
+                        vm.created_at.clone().unwrap_or_default(),
+                        vm.autostart.to_string(),
+                        vm.tags.join(" "),
+                    ]
+                })
+                .collect();
+            helpers::print_csv(
+                &[
+                    "name",
+                    "state",
+                    "vcpus",
+                    "memory_kib",
+                    "ips",
+                    "image",
+                    "created_at",
+                    "autostart",
+                    "tags",
+                ],
+                &rows,
+                options.no_headers,
+            );
+        }
+        _ => {
+            let rows: Vec<Vec<String>> = vms
+                .iter()
+                .map(|vm| {
+                    vec![
+                        vm.name.clone(),
+                        vm.state.clone(),
+                        vm.vcpus.to_string(),
+                        utils::format_bytes(vm.memory_kib * 1024),
+                        or_dash(vm.addresses.join(",")),
+                        vm.image.clone().unwrap_or("-".to_string()),
+                        vm.age_seconds
+                            .map(utils::format_age)
+                            .unwrap_or("-".to_string()),
+                        if vm.autostart { "yes" } else { "no" }.to_string(),
+                        or_dash(vm.tags.join(",")),
+                    ]
+                })
+                .collect();
+            helpers::print_table(
+                &[
+                    "NAME",
+                    "STATE",
+                    "VCPUS",
+                    "MEMORY",
+                    "IPS",
+                    "IMAGE",
+                    "AGE",
+                    "AUTOSTART",
+                    "TAGS",
+                ],
+                &rows,
+                options.no_headers,
+            );
+        }
     }
 }
 
-pub fn shutdown_vm(name: &str) {
-    println!("Shutting down VM: {}", name);
-    let mut conn = Connect::open(Some("qemu:///system")).unwrap();
-    let domain = Domain::lookup_by_name(&mut conn, name).unwrap();
+fn or_dash(value: String) -> String {
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
 
-    if domain.is_active().unwrap() {
-        domain.shutdown().unwrap();
-        let mut timeout = 10; // seconds
-        while domain.is_active().unwrap() && timeout > 0 {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            timeout -= 1;
-        }
-        if domain.is_active().unwrap() {
-            println!("Graceful shutdown timed out, forcing power off.");
-            domain.destroy().unwrap();
-        } else {
-            println!("Domain {} has been shut down gracefully.", name);
+/// Shut down one VM, or every running VM when `name` is `None`, in parallel.
+/// `mode` is `acpi`, `agent` or `signal`. VMs still running after `timeout`
+/// seconds are powered off unless `force` is false.
+pub fn shutdown_vm(name: Option<&str>, mode: &str, timeout: u64, force: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domains = match name {
+        Some(name) => {
+            println!("Shutting down VM: {}", name);
+            vec![Domain::lookup_by_name(&conn, name).unwrap()]
         }
-    } else {
-        println!("Domain {} is not active.", name);
+        None => {
+            println!("Shutting down all running VMs");
+            conn.list_all_domains(virt_sys::VIR_CONNECT_LIST_DOMAINS_ACTIVE)
+                .unwrap()
+        }
+    };
+
+    let flags = match mode {
+        "agent" => virt_sys::VIR_DOMAIN_SHUTDOWN_GUEST_AGENT,
+        "signal" => virt_sys::VIR_DOMAIN_SHUTDOWN_SIGNAL,
+        _ => virt_sys::VIR_DOMAIN_SHUTDOWN_ACPI_POWER_BTN,
+    };
+    let timeout = Duration::from_secs(timeout);
+
+    let outcomes: Vec<(String, utils::ShutdownOutcome)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = domains
+            .iter()
+            .map(|domain| {
+                scope.spawn(move || {
+                    (
+                        domain.get_name().unwrap(),
+                        utils::shutdown_domain(domain, flags, timeout, force),
+                    )
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut failed = false;
+    for (name, outcome) in outcomes {
+        match outcome {
+            utils::ShutdownOutcome::Graceful => {
+                println!("Domain {} has been shut down gracefully.", name)
+            }
+            utils::ShutdownOutcome::Forced => {
+                println!("Domain {} did not stop in time and was powered off.", name)
+            }
+            utils::ShutdownOutcome::NotRunning => println!("Domain {} is not active.", name),
+            utils::ShutdownOutcome::Failed(e) => {
+                eprintln!("Domain {} failed to shut down: {}", name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
     }
 }
 
@@ -97,22 +807,909 @@ pub fn restart_vm(name: &str) {
     domain.reboot(0).unwrap();
 }
 
-pub fn vm_info(name: &str) {
-    println!("Getting info for VM: {}", name);
+pub fn pause_vm(name: &str) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    match domain.get_state().unwrap().0 {
+        virt_sys::VIR_DOMAIN_RUNNING | virt_sys::VIR_DOMAIN_BLOCKED => {}
+        virt_sys::VIR_DOMAIN_PAUSED => {
+            println!("VM {} is already paused.", name);
+            return;
+        }
+        state => {
+            eprintln!(
+                "Cannot pause VM {}: it is {}.",
+                name,
+                utils::display_state(&domain, state)
+            );
+            std::process::exit(1);
+        }
+    }
+
+    println!("Pausing VM: {}", name);
+    domain.suspend().unwrap();
+}
+
+pub fn resume_vm(name: &str) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    let state = domain.get_state().unwrap().0;
+    if state != virt_sys::VIR_DOMAIN_PAUSED {
+        eprintln!(
+            "Cannot resume VM {}: it is {}, not paused.",
+            name,
+            utils::display_state(&domain, state)
+        );
+        std::process::exit(1);
+    }
+
+    println!("Resuming VM: {}", name);
+    domain.resume().unwrap();
+}
+
+/// Write the memory of a running or paused VM to disk and stop it, freeing
+/// its host RAM. `restore` or `boot` picks up where it left off.
+pub fn save_vm(name: &str) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if !domain.is_active().unwrap() {
+        let state = domain.get_state().unwrap().0;
+        eprintln!(
+            "Cannot save VM {}: it is {}.",
+            name,
+            utils::display_state(&domain, state)
+        );
+        std::process::exit(1);
+    }
+
+    println!("Saving VM: {}", name);
+    domain.managed_save(0).unwrap();
+}
+
+/// Start a VM from the state written by `save`.
+pub fn restore_vm(name: &str) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if domain.is_active().unwrap() || !domain.has_managed_save(0).unwrap() {
+        eprintln!("Cannot restore VM {}: it has no saved state.", name);
+        std::process::exit(1);
+    }
+
+    println!("Restoring VM: {}", name);
+    domain.create().unwrap();
+}
+
+/// Print details of a VM as a table, or as `json` or `yaml`.
+pub fn vm_info(name: &str, output: &str) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+    let info = utils::collect_vm_info(&domain);
+
+    match output {
+        "json" => println!("{}", serde_json::to_string_pretty(&info).unwrap()),
+        "yaml" => print!("{}", serde_yml::to_string(&info).unwrap()),
+        _ => print_vm_info(&info),
+    }
+}
+
+fn print_vm_info(info: &types::VmInfo) {
+    let size = |bytes: Option<u64>| bytes.map(utils::format_bytes).unwrap_or("-".to_string());
+
+    println!("Name: {}", info.name);
+    println!("UUID: {}", info.uuid);
+    println!(
+        "ID: {}",
+        info.id
+            .map(|id| id.to_string())
+            .unwrap_or("N/A".to_string())
+    );
+    println!("State: {} ({})", info.state, info.state_reason);
+    println!("Persistent: {}", info.persistent);
+    println!("Autostart: {}", info.autostart);
+    println!(
+        "Memory: {} / {} max",
+        utils::format_bytes(info.memory_kib * 1024),
+        utils::format_bytes(info.max_memory_kib * 1024)
+    );
+    println!("vCPUs: {} / {} max", info.vcpus, info.max_vcpus);
+    for disk in &info.disks {
+        println!(
+            "Disk {}: {} {}, capacity {}, allocated {}{}",
+            disk.target,
+            disk.device,
+            disk.source.as_deref().unwrap_or("-"),
+            size(disk.capacity_bytes),
+            size(disk.allocation_bytes),
+            disk.backing_file
+                .as_ref()
+                .map(|b| format!(", backing {}", b))
+                .unwrap_or_default()
+        );
+    }
+    for nic in &info.nics {
+        println!(
+            "NIC {}: {} {}, model {}, IP {}",
+            nic.mac.as_deref().unwrap_or("-"),
+            nic.interface_type,
+            nic.source.as_deref().unwrap_or("-"),
+            nic.model.as_deref().unwrap_or("-"),
+            if nic.addresses.is_empty() {
+                "-".to_string()
+            } else {
+                nic.addresses.join(" ")
+            }
+        );
+    }
+    if let Some(graphics) = &info.graphics {
+        println!(
+            "Graphics: {} {}:{}",
+            graphics.graphics_type,
+            graphics.listen.as_deref().unwrap_or("127.0.0.1"),
+            graphics
+                .port
+                .map(|p| p.to_string())
+                .unwrap_or("-".to_string())
+        );
+    }
+    if let Some(username) = &info.username {
+        println!("User: {}", username);
+    }
+    if let Some(image) = &info.image {
+        println!("Image: {}", image);
+    }
+    if let Some(created_at) = &info.created_at {
+        println!("Created: {}", created_at);
+    }
+    if !info.tags.is_empty() {
+        println!("Tags: {}", info.tags.join(" "));
+    }
+}
+
+pub fn vm_ip(name: &str, timeout: u64) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if !domain.is_active().unwrap() {
+        eprintln!("Domain {} is not active.", name);
+        std::process::exit(1);
+    }
+    print_guest_addresses(name, &domain, timeout);
+}
+
+fn print_guest_addresses(name: &str, domain: &Domain, timeout: u64) {
+    let addresses = utils::wait_for_guest_addresses(domain, Duration::from_secs(timeout));
+    if addresses.is_empty() {
+        eprintln!("No IP address found for {}", name);
+        std::process::exit(1);
+    }
+    for address in addresses {
+        println!(
+            "Interface: {}, MAC: {}, IP: {}/{}, Source: {}",
+            address.interface, address.mac, address.address, address.prefix, address.source
+        );
+    }
+}
+
+/// Open an SSH session to a VM, or run `command` in it and exit with its status.
+pub fn ssh_vm(name: &str, user: Option<&str>, command: &[String]) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if !domain.is_active().unwrap() {
+        eprintln!("Domain {} is not active.", name);
+        std::process::exit(1);
+    }
+
+    let metadata = utils::get_vm_metadata(&domain);
+    let Some(username) = user
+        .map(str::to_string)
+        .or_else(|| metadata.as_ref().map(|m| m.username.clone()))
+    else {
+        eprintln!(
+            "No username recorded for {}, it was not created by vm-alloc. Pass --user.",
+            name
+        );
+        std::process::exit(1);
+    };
+
+    let address = utils::guest_addresses(&domain)
+        .into_iter()
+        .find(|a| a.address.parse::<std::net::Ipv4Addr>().is_ok());
+    let Some(address) = address else {
+        eprintln!("No IP address found for {}", name);
+        std::process::exit(1);
+    };
+
+    std::fs::create_dir_all(utils::STATE_DIR).expect("Failed to create vm-alloc state directory.");
+    let mut ssh = std::process::Command::new("ssh");
+    ssh.arg("-o")
+        .arg(format!("UserKnownHostsFile={}", utils::known_hosts_path()))
+        .args(["-o", "StrictHostKeyChecking=accept-new"])
+        .arg("-o")
+        .arg(format!("HostKeyAlias={}", name));
+    if let Some(key) = metadata.and_then(|m| m.ssh_key) {
+        ssh.arg("-i").arg(key).args(["-o", "IdentitiesOnly=yes"]);
+    }
+    ssh.arg(format!("{}@{}", username, address.address));
+    ssh.args(command);
+
+    let status = ssh.status().expect("Failed to execute ssh command");
+    std::process::exit(status.code().unwrap_or(255));
+}
+
+/// Run a command in a VM through the guest agent and exit with its status.
+pub fn exec_vm(name: &str, command: &[String], timeout: u64) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    match agent::guest_exec(&domain, command, Duration::from_secs(timeout)) {
+        Ok(output) => {
+            std::io::stdout().write_all(&output.stdout).unwrap();
+            std::io::stderr().write_all(&output.stderr).unwrap();
+            std::process::exit(output.exit_code as i32);
+        }
+        Err(e) => {
+            eprintln!("Error running command in {}: {}", name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Split a `cp` argument into the VM name and guest path when it has the
+/// `<vm>:<path>` form.
+fn split_guest_path(path: &str) -> Option<(&str, &str)> {
+    let (vm, guest_path) = path.split_once(':')?;
+    if vm.is_empty() || vm.contains('/') {
+        return None;
+    }
+    Some((vm, guest_path))
+}
+
+/// Copy a file into or out of a VM through the guest agent.
+pub fn copy_file(source: &str, destination: &str) {
+    let (name, result) = match (split_guest_path(source), split_guest_path(destination)) {
+        (None, Some((name, guest_path))) => {
+            let conn = Connect::open(Some("qemu:///system")).unwrap();
+            let domain = Domain::lookup_by_name(&conn, name).unwrap();
+            (name, agent::upload(&domain, source, guest_path))
+        }
+        (Some((name, guest_path)), None) => {
+            let conn = Connect::open(Some("qemu:///system")).unwrap();
+            let domain = Domain::lookup_by_name(&conn, name).unwrap();
+            (name, agent::download(&domain, guest_path, destination))
+        }
+        _ => {
+            eprintln!("Exactly one of source and destination must be <vm>:<path>");
+            std::process::exit(1);
+        }
+    };
+
+    match result {
+        Ok(bytes) => println!("Copied {} bytes.", bytes),
+        Err(e) => {
+            eprintln!("Error copying file with {}: {}", name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Attach to the serial console of a VM, optionally copying its output to `log`.
+pub fn console_vm(name: &str, log: Option<&str>, force: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if !domain.is_active().unwrap() {
+        eprintln!("Domain {} is not active.", name);
+        std::process::exit(1);
+    }
+
+    let log = log.map(|path| {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Unable to open console log file")
+    });
+
+    println!(
+        "Connected to the console of {} (escape character is ^])",
+        name
+    );
+    let stream = Stream::new(&conn, 0).unwrap();
+    if let Err(e) = console::attach(&domain, stream, force, log) {
+        eprintln!("\r\nError on console of {}: {}", name, e);
+        std::process::exit(1);
+    }
+    println!("\r\nDisconnected from the console of {}", name);
+}
+
+/// Print the serial console log of a VM, then keep printing new output when
+/// `follow` is set.
+pub fn logs_vm(name: &str, follow: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    let domain_config: types::DomainConfig =
+        helpers::xml_to_struct(&domain.get_xml_desc(0).unwrap());
+    let log_path = domain_config
+        .devices
+        .and_then(|d| d.serial)
+        .and_then(|s| s.log)
+        .map(|l| l.file)
+        .unwrap_or_else(|| utils::serial_log_path(name));
+
+    let mut file = match std::fs::File::open(&log_path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error opening log {}: {}", log_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut stdout = std::io::stdout();
+    let mut buf = [0; 8192];
+    loop {
+        let n = file.read(&mut buf).unwrap();
+        if n > 0 {
+            stdout.write_all(&buf[..n]).unwrap();
+            continue;
+        }
+        if !follow {
+            break;
+        }
+        stdout.flush().unwrap();
+        // Start over if the log was truncated
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        if file.stream_position().unwrap() > len {
+            file.seek(SeekFrom::Start(0)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Print where to connect to the graphical display of a running VM.
+pub fn display_vm(name: &str) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if !domain.is_active().unwrap() {
+        eprintln!("Domain {} is not active.", name);
+        std::process::exit(1);
+    }
+
+    let domain_config: types::DomainConfig =
+        helpers::xml_to_struct(&domain.get_xml_desc(0).unwrap());
+    let Some(graphics) = domain_config.devices.and_then(|d| d.graphics) else {
+        println!("Domain {} has no graphical display.", name);
+        return;
+    };
+
+    // A wildcard listen address is reachable through the host name
+    let host = match graphics.listen.as_deref() {
+        None | Some("0.0.0.0") | Some("::") => conn.get_hostname().unwrap(),
+        Some(listen) => listen.to_string(),
+    };
+    let Some(port) = graphics.port.filter(|p| p != "-1") else {
+        eprintln!("Domain {} has no display port assigned.", name);
+        std::process::exit(1);
+    };
+    println!("Type: {}", graphics.graphics_type);
+    println!("Address: {}:{}", host, port);
+    println!("URI: {}://{}:{}", graphics.graphics_type, host, port);
+}
 
+/// Print the resource usage of a running VM every `interval` seconds, `count`
+/// times or until interrupted.
+pub fn stats_vm(name: &str, interval: u64, count: Option<u64>) {
     let conn = Connect::open(Some("qemu:///system")).unwrap();
     let domain = Domain::lookup_by_name(&conn, name).unwrap();
 
-    // state (the tuple contents/shape depend on the binding; printing for debugging)
-    if let Ok(state) = domain.get_state() {
-        println!("State code: {:?}, reason: {:?}", state.0, state.1);
+    if !domain.is_active().unwrap() {
+        eprintln!("Domain {} is not active.", name);
+        std::process::exit(1);
     }
 
-    // mem / vcpus
-    if let Ok(max_mem) = domain.get_max_memory() {
-        println!("Max memory: {} KiB", max_mem);
+    let print_row = |cells: &[String]| {
+        println!(
+            "{:>5}  {:>6}  {:>21}  {:>12}  {:>12}  {:>12}  {:>12}",
+            cells[1], cells[2], cells[3], cells[4], cells[5], cells[6], cells[7]
+        );
+    };
+    print_row(&stats::USAGE_HEADERS.map(String::from));
+
+    let mut previous = match stats::sample(&domain) {
+        Ok(sample) => sample,
+        Err(e) => {
+            eprintln!("Could not sample domain {}: {}", name, e);
+            std::process::exit(1);
+        }
+    };
+    let mut printed = 0;
+    while count.is_none_or(|count| printed < count) {
+        std::thread::sleep(Duration::from_secs(interval));
+        let current = match stats::sample(&domain) {
+            Ok(sample) => sample,
+            Err(e) => {
+                eprintln!("Could not sample domain {}: {}", name, e);
+                std::process::exit(1);
+            }
+        };
+        print_row(&stats::usage_row(&stats::usage(name, &previous, &current)));
+        previous = current;
+        printed += 1;
     }
-    if let Ok(vcpus) = domain.get_max_vcpus() {
-        println!("vCPUs: {}", vcpus);
+}
+
+/// Show a table of the resource usage of all running VMs, refreshed every
+/// `interval` seconds. Pressing c, m, d, n or N sorts by CPU, memory, disk,
+/// network or name, and q quits.
+pub fn top_vms(interval: u64, sort: &str, count: Option<u64>) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let mut sort = sort.to_string();
+    // Samples of the previous round, by VM name, to compute rates against
+    let mut previous: HashMap<String, stats::Sample> = HashMap::new();
+    let mut usages: Vec<stats::Usage> = Vec::new();
+
+    let _keys = console::RawTerminal::keypresses();
+    let (key_tx, key_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0; 1];
+        while let Ok(1) = std::io::stdin().read(&mut buf) {
+            if key_tx.send(buf[0]).is_err() {
+                break;
+            }
+        }
+    });
+
+    println!("Collecting samples...");
+    let mut refreshes = 0;
+    loop {
+        let domains = conn
+            .list_all_domains(virt_sys::VIR_CONNECT_LIST_DOMAINS_ACTIVE)
+            .unwrap();
+        let mut current = HashMap::new();
+        usages.clear();
+        for domain in domains {
+            let name = domain.get_name().unwrap();
+            // Domains can stop between listing and sampling
+            let Ok(sample) = stats::sample(&domain) else {
+                continue;
+            };
+            if let Some(before) = previous.get(&name) {
+                usages.push(stats::usage(&name, before, &sample));
+            }
+            current.insert(name, sample);
+        }
+        previous = current;
+        let next_refresh = Instant::now() + Duration::from_secs(interval);
+
+        // The first round only collects the samples to compare against
+        if refreshes == 0 {
+            std::thread::sleep(Duration::from_secs(interval));
+            refreshes += 1;
+            continue;
+        }
+
+        loop {
+            stats::sort_usages(&mut usages, &sort);
+            let rows: Vec<Vec<String>> = usages.iter().map(stats::usage_row).collect();
+            print!("\x1b[2J\x1b[H");
+            println!(
+                "{} running VMs, sorted by {} (c/m/d/n/N to sort, q to quit)\n",
+                previous.len(),
+                sort
+            );
+            helpers::print_table(&stats::USAGE_HEADERS, &rows, false);
+            std::io::stdout().flush().unwrap();
+
+            let sort_key =
+                match key_rx.recv_timeout(next_refresh.saturating_duration_since(Instant::now())) {
+                    Ok(b'q') => return,
+                    Ok(key) => key,
+                    Err(_) => break,
+                };
+            sort = match sort_key {
+                b'c' => "cpu",
+                b'm' => "memory",
+                b'd' => "disk",
+                b'n' => "net",
+                b'N' => "name",
+                _ => continue,
+            }
+            .to_string();
+        }
+
+        if count.is_some_and(|count| refreshes >= count) {
+            return;
+        }
+        refreshes += 1;
+    }
+}
+
+/// Print domain events as they happen, for one VM or all of them, until
+/// interrupted. `output` is `text` or `json`, one object per line.
+pub fn watch_events(name: Option<&str>, output: &str) {
+    let mut stream = match events::subscribe(name) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Could not watch events: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        while let Some(event) = stream.next().await {
+            match output {
+                "json" => println!("{}", serde_json::to_string(&event).unwrap()),
+                _ => println!("{}", event),
+            }
+        }
+    });
+    eprintln!("Lost the connection to libvirt.");
+    std::process::exit(1);
+}
+
+/// Change the memory (in MiB) and vCPUs of a VM. Without `live` or `config`
+/// both the running VM and its persistent config are changed. The running VM
+/// can only grow up to the maxima it was started with; the config maxima are
+/// raised as needed.
+pub fn resize_vm(name: &str, memory: Option<u64>, vcpus: Option<u32>, live: bool, config: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    let mut flags = 0;
+    if live {
+        flags |= virt_sys::VIR_DOMAIN_AFFECT_LIVE;
+    }
+    if config {
+        flags |= virt_sys::VIR_DOMAIN_AFFECT_CONFIG;
+    }
+    if flags == 0 {
+        flags = utils::modification_flags(&domain, true);
+    }
+    if flags & virt_sys::VIR_DOMAIN_AFFECT_LIVE != 0 && !domain.is_active().unwrap() {
+        eprintln!(
+            "VM {} is not running, use --config to change its configuration.",
+            name
+        );
+        std::process::exit(1);
+    }
+    let affects_live = flags & virt_sys::VIR_DOMAIN_AFFECT_LIVE != 0;
+    let affects_config = flags & virt_sys::VIR_DOMAIN_AFFECT_CONFIG != 0;
+
+    if let Some(memory) = memory {
+        let memory_kib = memory * 1024;
+        if affects_live {
+            let max_kib = domain.get_info().unwrap().max_mem;
+            if memory_kib > max_kib {
+                eprintln!(
+                    "VM {} was started with at most {} MiB, use --config to apply {} MiB on its next boot.",
+                    name,
+                    max_kib / 1024,
+                    memory
+                );
+                std::process::exit(1);
+            }
+        }
+        if affects_config {
+            let domain_config: types::DomainConfig = helpers::xml_to_struct(
+                &domain
+                    .get_xml_desc(virt_sys::VIR_DOMAIN_XML_INACTIVE)
+                    .unwrap(),
+            );
+            let max_kib = domain_config
+                .memory
+                .as_ref()
+                .map(utils::memory_kib)
+                .unwrap_or(0);
+            if memory_kib > max_kib {
+                domain
+                    .set_memory_flags(
+                        memory_kib,
+                        virt_sys::VIR_DOMAIN_MEM_CONFIG | virt_sys::VIR_DOMAIN_MEM_MAXIMUM,
+                    )
+                    .unwrap();
+            }
+        }
+        if let Err(e) = domain.set_memory_flags(memory_kib, flags) {
+            eprintln!("Error setting memory: {}", e);
+            std::process::exit(1);
+        }
+        println!("Set memory of {} to {} MiB", name, memory);
+    }
+
+    if let Some(vcpus) = vcpus {
+        if affects_live {
+            let max = domain
+                .get_vcpus_flags(virt_sys::VIR_DOMAIN_VCPU_LIVE | virt_sys::VIR_DOMAIN_VCPU_MAXIMUM)
+                .unwrap();
+            if vcpus > max {
+                eprintln!(
+                    "VM {} was started with at most {} vCPUs, use --config to apply {} vCPUs on its next boot.",
+                    name, max, vcpus
+                );
+                std::process::exit(1);
+            }
+        }
+        if affects_config {
+            let max = domain
+                .get_vcpus_flags(
+                    virt_sys::VIR_DOMAIN_VCPU_CONFIG | virt_sys::VIR_DOMAIN_VCPU_MAXIMUM,
+                )
+                .unwrap();
+            if vcpus > max {
+                domain
+                    .set_vcpus_flags(
+                        vcpus,
+                        virt_sys::VIR_DOMAIN_VCPU_CONFIG | virt_sys::VIR_DOMAIN_VCPU_MAXIMUM,
+                    )
+                    .unwrap();
+            }
+        }
+        if let Err(e) = domain.set_vcpus_flags(vcpus, flags) {
+            eprintln!("Error setting vCPUs: {}", e);
+            std::process::exit(1);
+        }
+        println!("Set vCPUs of {} to {}", name, vcpus);
+    }
+}
+
+pub fn throttle_disk(name: &str, dev: &str, iotune: &types::Iotune, persist: bool) {
+    println!("Throttling disk {} of VM: {}", dev, name);
+
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    let flags = utils::modification_flags(&domain, persist);
+    if let Err(e) = utils::set_block_io_tune(&domain, dev, iotune, flags) {
+        eprintln!("Error throttling disk {}: {}", dev, e);
+        std::process::exit(1);
+    }
+    println!("IO limits applied to {} on {}.", dev, name);
+}
+
+/// Grow the partition that ends last on a disk, and the filesystem on it, from
+/// inside the guest. `$1` is the disk device.
+const GUEST_GROW_DISK: &str = r#"set -e
+disk=$1
+part=
+start=-1
+for file in /sys/class/block/"${disk##*/}"/*/start; do
+    [ -e "$file" ] || continue
+    if [ "$(cat "$file")" -gt "$start" ]; then
+        start=$(cat "$file")
+        part=$(basename "$(dirname "$file")")
+    fi
+done
+if [ -n "$part" ]; then
+    growpart "$disk" "$(cat /sys/class/block/"$part"/partition)"
+    part=/dev/$part
+else
+    part=$disk
+fi
+case $(lsblk -no FSTYPE "$part") in
+    ext*) resize2fs "$part" ;;
+    xfs) xfs_growfs "$(findmnt -nro TARGET -S "$part" | head -n 1)" ;;
+esac"#;
+
+/// Parse a number from domain XML, which libvirt writes in hex for addresses.
+fn parse_xml_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Find the guest device of disk `dev` with `guest-get-disks`. The guest can
+/// name disks differently than libvirt's target, so it is matched on the
+/// disk's serial or its PCI or drive address instead.
+fn guest_disk_device(domain: &Domain, dev: &str) -> Result<String, String> {
+    let mut domain_xml = helpers::parse_xml(&domain.get_xml_desc(0).unwrap());
+    let devices_xml = domain_xml.get_mut_child("devices").unwrap();
+    let disk = helpers::children_mut(devices_xml, "disk")
+        .find(|d| {
+            d.get_child("target")
+                .and_then(|t| t.attributes.get("dev"))
+                .is_some_and(|t| t == dev)
+        })
+        .ok_or(format!("no disk {}", dev))?;
+    let serial = disk
+        .get_child("serial")
+        .and_then(|s| s.get_text())
+        .map(|s| s.to_string());
+    let address = disk.get_child("address").map(|a| a.attributes.clone());
+    let attribute = |name: &str| {
+        address
+            .as_ref()
+            .and_then(|a| a.get(name))
+            .and_then(|v| parse_xml_number(v))
+    };
+
+    let disks = agent::agent_command(domain, &serde_json::json!({ "execute": "guest-get-disks" }))?;
+    let device = disks
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|d| d["partition"] == false)
+        .find(|d| {
+            let guest = &d["address"];
+            if let Some(serial) = &serial {
+                return guest["serial"].as_str() == Some(serial.as_str());
+            }
+            match address
+                .as_ref()
+                .and_then(|a| a.get("type"))
+                .map(String::as_str)
+            {
+                Some("pci") => ["domain", "bus", "slot", "function"]
+                    .iter()
+                    .all(|field| guest["pci-controller"][field].as_u64() == attribute(field)),
+                Some("drive") => ["bus", "target", "unit"]
+                    .iter()
+                    .all(|field| guest[field].as_u64() == attribute(field)),
+                _ => false,
+            }
+        })
+        .and_then(|d| d["name"].as_str())
+        .ok_or(format!("the guest does not report disk {}", dev))?;
+    Ok(device.to_string())
+}
+
+fn grow_guest_disk(domain: &Domain, dev: &str) -> Result<(), String> {
+    let device = guest_disk_device(domain, dev)?;
+    let args = ["sh", "-c", GUEST_GROW_DISK, "sh", &device].map(String::from);
+    let output = agent::guest_exec(domain, &args, Duration::from_secs(120))?;
+    if output.exit_code != 0 {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+/// Resize disk `dev` of a VM to `size` bytes. A running VM is resized live and
+/// its partition and filesystem grown through the guest agent. For a stopped
+/// one the image is resized, and cloud-init grows the root partition on the
+/// next boot.
+pub fn resize_disk(name: &str, dev: &str, size: u64, allow_shrink: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if domain.has_managed_save(0).unwrap() {
+        eprintln!(
+            "VM {} has a saved state, restore it before resizing its disks.",
+            name
+        );
+        std::process::exit(1);
+    }
+
+    let domain_config: types::DomainConfig =
+        helpers::xml_to_struct(&domain.get_xml_desc(0).unwrap());
+    let path = domain_config
+        .devices
+        .into_iter()
+        .flat_map(|d| d.disk)
+        .filter(|d| d.device == "disk")
+        .find(|d| d.target.as_ref().is_some_and(|t| t.dev == dev))
+        .and_then(|d| d.source)
+        .and_then(|s| s.file);
+    let Some(path) = path else {
+        eprintln!("VM {} has no disk {}.", name, dev);
+        std::process::exit(1);
+    };
+
+    let capacity = domain.get_block_info(dev, 0).unwrap().capacity;
+    if size == capacity {
+        println!(
+            "Disk {} of {} already is {}.",
+            dev,
+            name,
+            utils::format_bytes(size)
+        );
+        return;
+    }
+    let shrink = size < capacity;
+    if shrink && !allow_shrink {
+        eprintln!(
+            "Refusing to shrink disk {} of {} from {} to {}, shrink the guest filesystem first and pass --allow-shrink.",
+            dev,
+            name,
+            utils::format_bytes(capacity),
+            utils::format_bytes(size)
+        );
+        std::process::exit(1);
+    }
+
+    println!(
+        "Resizing disk {} of VM {} from {} to {}",
+        dev,
+        name,
+        utils::format_bytes(capacity),
+        utils::format_bytes(size)
+    );
+    if domain.is_active().unwrap() {
+        if let Err(e) = domain.block_resize(dev, size, virt_sys::VIR_DOMAIN_BLOCK_RESIZE_BYTES) {
+            eprintln!("Error resizing disk {}: {}", dev, e);
+            std::process::exit(1);
+        }
+        if shrink {
+            return;
+        }
+        match grow_guest_disk(&domain, dev) {
+            Ok(()) => println!("Grew the partition and filesystem on {} in the guest.", dev),
+            Err(e) => println!(
+                "Could not grow the partition in the guest ({}), if {} holds the root filesystem cloud-init grows it on the next boot.",
+                e, dev
+            ),
+        }
+    } else {
+        if let Err(e) = utils::resize_disk_image(&path, size, shrink) {
+            eprintln!("Error resizing disk {}: {}", dev, e);
+            std::process::exit(1);
+        }
+        if !shrink {
+            println!(
+                "If {} holds the root filesystem, cloud-init grows it on the next boot.",
+                dev
+            );
+        }
+    }
+}
+
+pub fn attach_nic(name: &str, interface: &types::Interface, persist: bool) {
+    println!("Attaching {} NIC to VM: {}", interface.interface_type, name);
+
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    let flags = utils::modification_flags(&domain, persist);
+    let interface_xml = helpers::struct_to_xml(interface, "interface");
+    if let Err(e) = domain.attach_device_flags(&interface_xml, flags) {
+        eprintln!("Error attaching NIC: {}", e);
+        std::process::exit(1);
+    }
+    println!("NIC attached to {}.", name);
+}
+
+pub fn detach_nic(name: &str, mac: &str, persist: bool) {
+    println!("Detaching NIC {} from VM: {}", mac, name);
+
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    let flags = utils::modification_flags(&domain, persist);
+    let xml_flags = if flags & virt_sys::VIR_DOMAIN_AFFECT_LIVE == 0 {
+        virt_sys::VIR_DOMAIN_XML_INACTIVE
+    } else {
+        0
+    };
+    let domain_config: types::DomainConfig =
+        helpers::xml_to_struct(&domain.get_xml_desc(xml_flags).unwrap());
+
+    let interface = domain_config
+        .devices
+        .into_iter()
+        .flat_map(|devices| devices.interface)
+        .find(|i| {
+            i.mac
+                .as_ref()
+                .is_some_and(|m| m.address.eq_ignore_ascii_case(mac))
+        });
+    let Some(interface) = interface else {
+        eprintln!("VM {} has no NIC with MAC address {}", name, mac);
+        std::process::exit(1);
+    };
+
+    let interface_xml = helpers::struct_to_xml(&interface, "interface");
+    if let Err(e) = domain.detach_device_flags(&interface_xml, flags) {
+        eprintln!("Error detaching NIC: {}", e);
+        std::process::exit(1);
     }
+    println!("NIC {} detached from {}.", mac, name);
 }
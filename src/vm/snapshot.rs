@@ -0,0 +1,242 @@
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use virt::connect::Connect;
+use virt::domain::Domain;
+use virt::domain_snapshot::DomainSnapshot;
+
+use crate::helpers;
+use crate::vm::types;
+
+fn lookup(conn: &Connect, name: &str, snapshot: &str) -> DomainSnapshot {
+    let domain = Domain::lookup_by_name(conn, name).unwrap();
+    match DomainSnapshot::lookup_by_name(&domain, snapshot, 0) {
+        Ok(snap) => snap,
+        Err(_) => {
+            eprintln!("VM {} has no snapshot named {}.", name, snapshot);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Build the snapshot XML. Internal snapshots are kept inside the qcow2 disks
+/// and include the memory of a running VM. External ones put each disk behind
+/// a new overlay next to it, and save memory to a file unless `disk_only`.
+fn snapshot_xml(
+    domain: &Domain,
+    snapshot: &str,
+    description: Option<String>,
+    external: bool,
+    disk_only: bool,
+) -> String {
+    let mut config = types::SnapshotConfig {
+        name: snapshot.to_string(),
+        description,
+        state: None,
+        creation_time: None,
+        parent: None,
+        memory: None,
+        disks: None,
+    };
+    if !external {
+        return helpers::struct_to_xml(&config, "domainsnapshot");
+    }
+
+    let name = domain.get_name().unwrap();
+    let domain_config: types::DomainConfig =
+        helpers::xml_to_struct(&domain.get_xml_desc(0).unwrap());
+    let mut memory_dir = None;
+    let disks = domain_config
+        .devices
+        .into_iter()
+        .flat_map(|d| d.disk)
+        .filter_map(|disk| {
+            let target = disk.target?.dev;
+            // The seed ISO and other read-only media are left alone
+            let Some(file) = disk
+                .source
+                .and_then(|s| s.file)
+                .filter(|_| disk.device == "disk")
+            else {
+                return Some(types::SnapshotDisk {
+                    name: target,
+                    snapshot: "no".to_string(),
+                    source: None,
+                });
+            };
+            let dir = Path::new(&file).parent().unwrap().to_path_buf();
+            let overlay = dir.join(format!("{}-{}-{}.qcow2", name, snapshot, target));
+            memory_dir.get_or_insert(dir);
+            Some(types::SnapshotDisk {
+                name: target,
+                snapshot: "external".to_string(),
+                source: Some(types::Source {
+                    file: Some(overlay.display().to_string()),
+                    ..Default::default()
+                }),
+            })
+        })
+        .collect();
+    config.disks = Some(types::SnapshotDisks { disk: disks });
+
+    if !disk_only && domain.is_active().unwrap() {
+        let dir = memory_dir.unwrap_or_else(|| Path::new(".").to_path_buf());
+        config.memory = Some(types::SnapshotMemory {
+            snapshot: "external".to_string(),
+            file: Some(
+                dir.join(format!("{}-{}.mem", name, snapshot))
+                    .display()
+                    .to_string(),
+            ),
+        });
+    }
+    helpers::struct_to_xml(&config, "domainsnapshot")
+}
+
+/// Turn the current disk images of a VM into read-only bases by moving it onto
+/// new overlays with an external disk-only snapshot.
+pub fn freeze_disks(domain: &Domain, snapshot: &str) -> Result<(), String> {
+    let xml = snapshot_xml(domain, snapshot, None, true, true);
+    DomainSnapshot::create_xml(
+        domain,
+        &xml,
+        virt_sys::VIR_DOMAIN_SNAPSHOT_CREATE_ATOMIC
+            | virt_sys::VIR_DOMAIN_SNAPSHOT_CREATE_DISK_ONLY,
+    )
+    .map(|_| ())
+    .map_err(|e| format!("could not snapshot {}: {}", snapshot, e))
+}
+
+/// Undo `freeze_disks` by deleting its snapshot, which has libvirt merge the
+/// overlays back into the disk images.
+pub fn unfreeze_disks(domain: &Domain, snapshot: &str) -> Result<(), String> {
+    DomainSnapshot::lookup_by_name(domain, snapshot, 0)
+        .and_then(|snap| snap.delete(0))
+        .map(|_| ())
+        .map_err(|e| format!("could not delete snapshot {}: {}", snapshot, e))
+}
+
+pub fn create_snapshot(
+    name: &str,
+    snapshot: &str,
+    description: Option<String>,
+    external: bool,
+    disk_only: bool,
+) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+    let xml = snapshot_xml(&domain, snapshot, description, external, disk_only);
+
+    let mut flags = virt_sys::VIR_DOMAIN_SNAPSHOT_CREATE_ATOMIC;
+    if disk_only {
+        flags |= virt_sys::VIR_DOMAIN_SNAPSHOT_CREATE_DISK_ONLY;
+    } else if external && domain.is_active().unwrap() {
+        // Keep the guest running while its memory is written out
+        flags |= virt_sys::VIR_DOMAIN_SNAPSHOT_CREATE_LIVE;
+    }
+
+    println!("Creating snapshot {} of VM {}", snapshot, name);
+    if let Err(e) = DomainSnapshot::create_xml(&domain, &xml, flags) {
+        eprintln!("Could not create snapshot: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// One line describing a snapshot: name, current marker, creation time, state
+/// and kind.
+fn describe(snap: &DomainSnapshot) -> String {
+    let config: types::SnapshotConfig = helpers::xml_to_struct(&snap.get_xml_desc(0).unwrap());
+    let created = config
+        .creation_time
+        .and_then(|t| t.parse().ok())
+        .map(|t| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(t)).to_string())
+        .unwrap_or("-".to_string());
+    let external = config
+        .disks
+        .iter()
+        .flat_map(|d| &d.disk)
+        .any(|d| d.snapshot == "external");
+    format!(
+        "{}{}  {}  {}  {}",
+        config.name,
+        if snap.is_current(0).unwrap_or(false) {
+            " (current)"
+        } else {
+            ""
+        },
+        created,
+        config.state.unwrap_or("-".to_string()),
+        if external { "external" } else { "internal" }
+    )
+}
+
+fn print_tree(snap: &DomainSnapshot, prefix: &str, last: bool) {
+    println!(
+        "{}{}{}",
+        prefix,
+        if last { "└── " } else { "├── " },
+        describe(snap)
+    );
+    let children = snap.list_all_children(0).unwrap();
+    let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+    for (i, child) in children.iter().enumerate() {
+        print_tree(child, &child_prefix, i == children.len() - 1);
+    }
+}
+
+/// Print the snapshots of a VM, or the tree they form through their parents.
+pub fn list_snapshots(name: &str, tree: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if !tree {
+        for snap in domain.list_all_snapshots(0).unwrap() {
+            println!("{}", describe(&snap));
+        }
+        return;
+    }
+
+    let roots = domain
+        .list_all_snapshots(virt_sys::VIR_DOMAIN_SNAPSHOT_LIST_ROOTS)
+        .unwrap();
+    println!("{}", name);
+    for (i, root) in roots.iter().enumerate() {
+        print_tree(root, "", i == roots.len() - 1);
+    }
+}
+
+/// Revert a VM to a snapshot. It ends up in the state it was in when the
+/// snapshot was taken, unless `running` or `paused` asks for one.
+pub fn revert_snapshot(name: &str, snapshot: &str, running: bool, paused: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let snap = lookup(&conn, name, snapshot);
+
+    let flags = if running {
+        virt_sys::VIR_DOMAIN_SNAPSHOT_REVERT_RUNNING
+    } else if paused {
+        virt_sys::VIR_DOMAIN_SNAPSHOT_REVERT_PAUSED
+    } else {
+        0
+    };
+    println!("Reverting VM {} to snapshot {}", name, snapshot);
+    if let Err(e) = snap.revert(flags) {
+        eprintln!("Could not revert to snapshot: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Delete a snapshot, and the snapshots based on it when `children` is set.
+pub fn delete_snapshot(name: &str, snapshot: &str, children: bool) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let snap = lookup(&conn, name, snapshot);
+
+    let flags = if children {
+        virt_sys::VIR_DOMAIN_SNAPSHOT_DELETE_CHILDREN
+    } else {
+        0
+    };
+    println!("Deleting snapshot {} of VM {}", snapshot, name);
+    if let Err(e) = snap.delete(flags) {
+        eprintln!("Could not delete snapshot: {}", e);
+        std::process::exit(1);
+    }
+}
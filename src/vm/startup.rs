@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use virt::connect::Connect;
+use virt::domain::Domain;
+
+use crate::vm::{types, utils};
+
+/// Turn booting a VM with the host on or off. VMs without a startup order are
+/// left to libvirt, which boots all its autostart VMs at once. VMs with one,
+/// from `after` or `delay`, keep libvirt autostart off and are booted by
+/// `startup` instead, which a boot unit runs after libvirtd. Turning autostart
+/// off drops the startup order.
+pub fn set_autostart(name: &str, enabled: bool, after: &[String], delay: Option<u64>) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+    let mut metadata = utils::get_vm_metadata(&domain);
+
+    if !after.is_empty() || delay.is_some() {
+        if !enabled {
+            eprintln!("--after and --delay only apply when turning autostart on.");
+            std::process::exit(1);
+        }
+        let Some(metadata) = metadata.as_mut() else {
+            eprintln!(
+                "VM {} was not created by vm-alloc, cannot store its startup order.",
+                name
+            );
+            std::process::exit(1);
+        };
+        if after.iter().any(|a| a == name) {
+            eprintln!("VM {} cannot be booted after itself.", name);
+            std::process::exit(1);
+        }
+        metadata.startup = Some(types::Startup {
+            delay: delay.map(|d| d.to_string()),
+            after: after
+                .iter()
+                .map(|a| types::StartupDependency { name: a.clone() })
+                .collect(),
+        });
+        utils::set_vm_metadata(&domain, metadata).unwrap();
+    } else if !enabled && let Some(metadata) = metadata.as_mut() {
+        metadata.startup = None;
+        utils::set_vm_metadata(&domain, metadata).unwrap();
+    }
+
+    let ordered = metadata.is_some_and(|m| m.startup.is_some());
+    if let Err(e) = domain.set_autostart(enabled && !ordered) {
+        eprintln!("Error setting autostart: {}", e);
+        std::process::exit(1);
+    }
+    if enabled && ordered {
+        println!(
+            "Autostart enabled for {}, booted in order by `vm-alloc startup`",
+            name
+        );
+    } else {
+        println!(
+            "Autostart {} for {}",
+            if enabled { "enabled" } else { "disabled" },
+            name
+        );
+    }
+}
+
+/// A VM `startup` considers booting.
+struct StartupVm {
+    name: String,
+    startup: types::Startup,
+    domain: Domain,
+}
+
+/// Order VMs so each comes after the VMs it declares in `after`, keeping the
+/// given order otherwise. Dependencies outside `vms` are ignored. Returns the
+/// names left over when the dependencies form a cycle.
+fn boot_order(mut vms: Vec<StartupVm>) -> Result<Vec<StartupVm>, Vec<String>> {
+    let names: HashSet<String> = vms.iter().map(|vm| vm.name.clone()).collect();
+    let mut booted = HashSet::new();
+    let mut order = Vec::new();
+    while !vms.is_empty() {
+        let ready = vms.iter().position(|vm| {
+            vm.startup
+                .after
+                .iter()
+                .all(|d| booted.contains(&d.name) || !names.contains(&d.name))
+        });
+        let Some(ready) = ready else {
+            return Err(vms.into_iter().map(|vm| vm.name).collect());
+        };
+        let vm = vms.remove(ready);
+        booted.insert(vm.name.clone());
+        order.push(vm);
+    }
+    Ok(order)
+}
+
+/// Boot the autostart VMs carrying all of `filters`, each after the VMs it
+/// depends on and waiting its delay before booting the next one. Running VMs
+/// are skipped.
+pub fn startup_vms(filters: &[types::Tag]) {
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let mut vms: Vec<StartupVm> = conn
+        .list_all_domains(0)
+        .unwrap()
+        .into_iter()
+        .filter_map(|domain| {
+            let metadata = utils::get_vm_metadata(&domain)?;
+            if !utils::autostart_enabled(&domain, &metadata)
+                || !filters.iter().all(|f| utils::tags_match(&metadata.tags, f))
+            {
+                return None;
+            }
+            Some(StartupVm {
+                name: domain.get_name().unwrap(),
+                startup: metadata.startup.unwrap_or_default(),
+                domain,
+            })
+        })
+        .collect();
+    vms.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let order = match boot_order(vms) {
+        Ok(order) => order,
+        Err(cycle) => {
+            eprintln!(
+                "The startup dependencies of {} form a cycle.",
+                cycle.join(", ")
+            );
+            std::process::exit(1);
+        }
+    };
+    if order.is_empty() {
+        println!("No autostart VMs to start.");
+        return;
+    }
+
+    for (i, vm) in order.iter().enumerate() {
+        if vm.domain.is_active().unwrap() {
+            println!("VM {} is already running", vm.name);
+            continue;
+        }
+        println!("Booting VM: {}", vm.name);
+        // Its dependents would likely fail without it, so stop here
+        if let Err(e) = vm.domain.create() {
+            eprintln!("Error booting VM {}: {}", vm.name, e);
+            std::process::exit(1);
+        }
+
+        let delay: u64 = vm
+            .startup
+            .delay
+            .as_deref()
+            .and_then(|d| d.parse().ok())
+            .unwrap_or(0);
+        if delay > 0 && i + 1 < order.len() {
+            println!("Waiting {}s before booting the next VM", delay);
+            std::thread::sleep(Duration::from_secs(delay));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::helpers;
+use crate::vm::{types, utils};
+use std::time::Instant;
+use virt::domain::Domain;
+use virt::error::Error;
+
+/// Cumulative counters of a running domain at one point in time.
+pub struct Sample {
+    pub taken: Instant,
+    /// CPU time used by all vCPUs, in nanoseconds
+    pub cpu_time: u64,
+    pub vcpus: u32,
+    pub disk_read_bytes: u64,
+    pub disk_write_bytes: u64,
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
+    pub memory: MemoryUsage,
+}
+
+/// Memory figures in KiB. `available`, `unused` and `rss` need the guest's
+/// balloon driver or QEMU to report them and are `None` otherwise.
+pub struct MemoryUsage {
+    /// Memory currently given to the guest
+    pub actual: u64,
+    pub available: Option<u64>,
+    pub unused: Option<u64>,
+    /// Resident size of the QEMU process on the host
+    pub rss: Option<u64>,
+}
+
+impl MemoryUsage {
+    /// Memory in use inside the guest, or the host RSS when the guest does
+    /// not report it.
+    pub fn used(&self) -> u64 {
+        match (self.available, self.unused) {
+            (Some(available), Some(unused)) => available.saturating_sub(unused),
+            _ => self.rss.unwrap_or(self.actual),
+        }
+    }
+}
+
+/// Rates computed from two samples of the same domain.
+pub struct Usage {
+    pub name: String,
+    pub vcpus: u32,
+    /// Share of the domain's vCPUs in use, 100% meaning all of them are busy
+    pub cpu_percent: f64,
+    pub memory_used: u64,
+    pub memory_total: u64,
+    pub disk_read_rate: f64,
+    pub disk_write_rate: f64,
+    pub net_rx_rate: f64,
+    pub net_tx_rate: f64,
+}
+
+/// Read the counters of a running domain, summed over all its disks and NICs.
+pub fn sample(domain: &Domain) -> Result<Sample, Error> {
+    let info = domain.get_info()?;
+    let domain_config: types::DomainConfig = helpers::xml_to_struct(&domain.get_xml_desc(0)?);
+    let devices = domain_config.devices;
+
+    let (mut disk_read_bytes, mut disk_write_bytes) = (0, 0);
+    for disk in devices
+        .iter()
+        .flat_map(|d| &d.disk)
+        .filter(|d| d.device == "disk")
+    {
+        let Some(target) = &disk.target else {
+            continue;
+        };
+        if let Ok(stats) = domain.get_block_stats(&target.dev) {
+            disk_read_bytes += stats.rd_bytes.max(0) as u64;
+            disk_write_bytes += stats.wr_bytes.max(0) as u64;
+        }
+    }
+
+    // libvirt accepts the MAC address in place of the host side device name
+    let (mut net_rx_bytes, mut net_tx_bytes) = (0, 0);
+    for mac in devices
+        .iter()
+        .flat_map(|d| &d.interface)
+        .filter_map(|i| i.mac.as_ref())
+    {
+        if let Ok(stats) = domain.interface_stats(&mac.address) {
+            net_rx_bytes += stats.rx_bytes.max(0) as u64;
+            net_tx_bytes += stats.tx_bytes.max(0) as u64;
+        }
+    }
+
+    let mut memory = MemoryUsage {
+        actual: info.memory,
+        available: None,
+        unused: None,
+        rss: None,
+    };
+    for stat in domain.memory_stats(0).unwrap_or_default() {
+        match stat.tag {
+            virt_sys::VIR_DOMAIN_MEMORY_STAT_ACTUAL_BALLOON => memory.actual = stat.val,
+            virt_sys::VIR_DOMAIN_MEMORY_STAT_AVAILABLE => memory.available = Some(stat.val),
+            virt_sys::VIR_DOMAIN_MEMORY_STAT_UNUSED => memory.unused = Some(stat.val),
+            virt_sys::VIR_DOMAIN_MEMORY_STAT_RSS => memory.rss = Some(stat.val),
+            _ => {}
+        }
+    }
+
+    Ok(Sample {
+        taken: Instant::now(),
+        cpu_time: info.cpu_time,
+        vcpus: info.nr_virt_cpu,
+        disk_read_bytes,
+        disk_write_bytes,
+        net_rx_bytes,
+        net_tx_bytes,
+        memory,
+    })
+}
+
+/// Compute rates between two samples. Counters that went backwards, as they
+/// do when a VM restarts, count as zero.
+pub fn usage(name: &str, before: &Sample, after: &Sample) -> Usage {
+    let elapsed = after.taken.duration_since(before.taken).as_secs_f64();
+    let rate = |before: u64, after: u64| {
+        if elapsed > 0.0 {
+            after.saturating_sub(before) as f64 / elapsed
+        } else {
+            0.0
+        }
+    };
+    let cpu_seconds = after.cpu_time.saturating_sub(before.cpu_time) as f64 / 1e9;
+
+    Usage {
+        name: name.to_string(),
+        vcpus: after.vcpus,
+        cpu_percent: if elapsed > 0.0 {
+            100.0 * cpu_seconds / (elapsed * after.vcpus.max(1) as f64)
+        } else {
+            0.0
+        },
+        memory_used: after.memory.used(),
+        memory_total: after.memory.actual,
+        disk_read_rate: rate(before.disk_read_bytes, after.disk_read_bytes),
+        disk_write_rate: rate(before.disk_write_bytes, after.disk_write_bytes),
+        net_rx_rate: rate(before.net_rx_bytes, after.net_rx_bytes),
+        net_tx_rate: rate(before.net_tx_bytes, after.net_tx_bytes),
+    }
+}
+
+/// Sort usages by `column`: `cpu`, `memory`, `disk`, `net` or `name`. All but
+/// `name` sort the busiest VM first.
+pub fn sort_usages(usages: &mut [Usage], column: &str) {
+    let key = |u: &Usage| match column {
+        "memory" => u.memory_used as f64,
+        "disk" => u.disk_read_rate + u.disk_write_rate,
+        "net" => u.net_rx_rate + u.net_tx_rate,
+        _ => u.cpu_percent,
+    };
+    if column == "name" {
+        usages.sort_by(|a, b| a.name.cmp(&b.name));
+    } else {
+        usages.sort_by(|a, b| key(b).total_cmp(&key(a)).then(a.name.cmp(&b.name)));
+    }
+}
+
+pub const USAGE_HEADERS: [&str; 8] = [
+    "NAME",
+    "VCPUS",
+    "CPU%",
+    "MEMORY",
+    "DISK READ",
+    "DISK WRITE",
+    "NET RX",
+    "NET TX",
+];
+
+/// Table row matching `USAGE_HEADERS`.
+pub fn usage_row(usage: &Usage) -> Vec<String> {
+    let rate = |bytes: f64| format!("{}/s", utils::format_bytes(bytes as u64));
+    vec![
+        usage.name.clone(),
+        usage.vcpus.to_string(),
+        format!("{:.1}", usage.cpu_percent),
+        format!(
+            "{} / {}",
+            utils::format_bytes(usage.memory_used * 1024),
+            utils::format_bytes(usage.memory_total * 1024)
+        ),
+        rate(usage.disk_read_rate),
+        rate(usage.disk_write_rate),
+        rate(usage.net_rx_rate),
+        rate(usage.net_tx_rate),
+    ]
+}
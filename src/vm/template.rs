@@ -0,0 +1,123 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use virt::connect::Connect;
+use virt::domain::Domain;
+
+use crate::helpers;
+use crate::vm::{types, utils};
+
+/// Reset a stopped image with virt-sysprep, so clones get their own
+/// machine-id and SSH host keys and cloud-init runs on first boot.
+fn clean_image(path: &str) -> Result<(), String> {
+    let output = Command::new("virt-sysprep")
+        .args([
+            "-a",
+            path,
+            "--operations",
+            "machine-id,ssh-hostkeys,logfiles,bash-history,tmp-files",
+            "--run-command",
+            "cloud-init clean --logs --seed",
+        ])
+        .output()
+        .map_err(|e| {
+            format!(
+                "could not run virt-sysprep (is libguestfs installed?): {}",
+                e
+            )
+        })?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+/// Turn a VM into a template: shut it down, flatten its root disk into a
+/// standalone image under `TEMPLATE_DIR`, and strip what identifies the VM
+/// from that copy. The VM itself is left as it was. `create --image
+/// <template>` then backs new VMs by it.
+pub fn create_template(name: &str, template: &str) {
+    if template.is_empty() || template.contains('/') {
+        eprintln!("Invalid template name '{}'.", template);
+        std::process::exit(1);
+    }
+    let path = utils::template_path(template);
+    if Path::new(&path).exists() {
+        eprintln!("Template {} already exists.", template);
+        std::process::exit(1);
+    }
+
+    let conn = Connect::open(Some("qemu:///system")).unwrap();
+    let domain = Domain::lookup_by_name(&conn, name).unwrap();
+
+    if domain.is_active().unwrap() {
+        println!("Shutting down VM: {}", name);
+        match utils::shutdown_domain(&domain, 0, Duration::from_secs(60), false) {
+            utils::ShutdownOutcome::Graceful | utils::ShutdownOutcome::NotRunning => {}
+            _ => {
+                eprintln!(
+                    "VM {} did not shut down, not creating a template from it.",
+                    name
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let domain_config: types::DomainConfig = helpers::xml_to_struct(
+        &domain
+            .get_xml_desc(virt_sys::VIR_DOMAIN_XML_INACTIVE)
+            .unwrap(),
+    );
+    let disk = domain_config
+        .devices
+        .into_iter()
+        .flat_map(|d| d.disk)
+        .filter(|d| d.device == "disk")
+        .find_map(|d| d.source.and_then(|s| s.file));
+    let Some(disk) = disk else {
+        eprintln!("VM {} has no disk image.", name);
+        std::process::exit(1);
+    };
+
+    std::fs::create_dir_all(utils::TEMPLATE_DIR).expect("Failed to create template directory.");
+    println!("Flattening {} into {}", disk, path);
+    if let Err(e) = utils::clone_disk(&disk, &path, false) {
+        eprintln!("Error flattening disk: {}", e);
+        let _ = std::fs::remove_file(&path);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = clean_image(&path) {
+        eprintln!("Error cleaning template image: {}", e);
+        let _ = std::fs::remove_file(&path);
+        std::process::exit(1);
+    }
+    println!("Created template {}", template);
+}
+
+/// Print the templates `create --image` can use.
+pub fn list_templates() {
+    let Ok(entries) = std::fs::read_dir(utils::TEMPLATE_DIR) else {
+        return;
+    };
+    let mut rows: Vec<Vec<String>> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "qcow2" {
+                return None;
+            }
+            let metadata = path.metadata().ok()?;
+            Some(vec![
+                path.file_stem()?.to_string_lossy().into_owned(),
+                utils::format_bytes(metadata.len()),
+                metadata
+                    .modified()
+                    .map(|m| humantime::format_rfc3339_seconds(m).to_string())
+                    .unwrap_or("-".to_string()),
+            ])
+        })
+        .collect();
+    rows.sort();
+    helpers::print_table(&["NAME", "SIZE", "CREATED"], &rows, false);
+}
@@ -1,3 +1,4 @@
+use crate::helpers;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -25,6 +26,7 @@ pub struct Os {
     #[serde(rename = "type")]
     pub os_type: OsType,
 
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
     pub boot: Vec<Boot>,
     //#[serde(skip_serializing_if = "Option::is_none")]
     //pub cmdline: Option<String>,
@@ -48,6 +50,8 @@ pub struct Boot {
     pub dev: String,
 }
 
+/// `<memory>` is the maximum a domain can balloon up to, `<currentMemory>`
+/// what it gets.
 #[derive(Serialize, Deserialize)]
 pub struct Memory {
     #[serde(rename = "@unit")]
@@ -61,17 +65,41 @@ pub struct Memory {
 pub struct Vcpu {
     #[serde(rename = "@placement")]
     pub placement: String,
+    /// vCPUs online at boot, when fewer than the maximum in `value`
+    #[serde(rename = "@current", skip_serializing_if = "Option::is_none")]
+    pub current: Option<String>,
     #[serde(rename = "#text")]
     pub value: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Devices {
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
     pub disk: Vec<Disk>,
-    pub interface: Option<Interface>,
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
+    pub interface: Vec<Interface>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub graphics: Option<Graphics>,
     pub console: Option<Console>,
     pub serial: Option<Serial>,
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
+    pub channel: Vec<Channel>,
+}
+
+/// A virtio-serial channel, used for the QEMU guest agent.
+#[derive(Serialize, Deserialize)]
+pub struct Channel {
+    #[serde(rename = "@type")]
+    pub channel_type: String,
+    pub target: ChannelTarget,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelTarget {
+    #[serde(rename = "@type")]
+    pub type_: String,
+    #[serde(rename = "@name", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -86,6 +114,17 @@ pub struct Serial {
     #[serde(rename = "@type")]
     pub serial_type: String,
     pub target: Option<SerialTarget>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<SerialLog>,
+}
+
+/// Copy of everything written to the serial port, kept by virtlogd.
+#[derive(Serialize, Deserialize)]
+pub struct SerialLog {
+    #[serde(rename = "@file")]
+    pub file: String,
+    #[serde(rename = "@append", skip_serializing_if = "Option::is_none")]
+    pub append: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -115,6 +154,38 @@ pub struct Disk {
     pub target: Option<Target>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub readonly: Option<Empty>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iotune: Option<Iotune>,
+}
+
+/// Block IO throttling for a disk. Values are bytes or operations per second,
+/// the `_max` variants are the burst limits.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Iotune {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes_sec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_bytes_sec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_bytes_sec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_iops_sec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_iops_sec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_iops_sec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes_sec_max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_bytes_sec_max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_bytes_sec_max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_iops_sec_max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_iops_sec_max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_iops_sec_max: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -125,12 +196,18 @@ pub struct Driver {
     pub driver_type: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Source {
     #[serde(rename = "@file", skip_serializing_if = "Option::is_none")]
     pub file: Option<String>,
     #[serde(rename = "@bridge", skip_serializing_if = "Option::is_none")]
     pub bridge: Option<String>,
+    #[serde(rename = "@network", skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    #[serde(rename = "@dev", skip_serializing_if = "Option::is_none")]
+    pub dev: Option<String>,
+    #[serde(rename = "@mode", skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -141,15 +218,25 @@ pub struct Target {
     pub bus: String,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A NIC of type `network`, `bridge`, `direct` (macvtap) or `user` (slirp).
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Interface {
     #[serde(rename = "@type")]
     pub interface_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<Mac>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub source: Option<Source>,
     pub model: Option<Model>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Mac {
+    #[serde(rename = "@address")]
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Model {
     #[serde(rename = "@type")]
     pub model_type: String,
@@ -159,16 +246,74 @@ pub struct Model {
 pub struct Graphics {
     #[serde(rename = "@type")]
     pub graphics_type: String,
-    #[serde(rename = "@port")]
-    pub port: String,
+    /// Left out of the inactive XML of autoport SPICE displays
+    #[serde(rename = "@port", skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
     #[serde(rename = "@autoport")]
     pub autoport: String,
+    #[serde(rename = "@listen", skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    #[serde(rename = "@passwd", skip_serializing_if = "Option::is_none")]
+    pub passwd: Option<String>,
 }
 
 /// For <readonly/> empty tags
 #[derive(Serialize, Deserialize)]
 pub struct Empty {}
 
+// Snapshot related structs, for <domainsnapshot> XML
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// State of the domain when the snapshot was taken, set by libvirt
+    #[serde(skip_serializing)]
+    pub state: Option<String>,
+    /// Seconds since the epoch, set by libvirt
+    #[serde(rename = "creationTime", skip_serializing)]
+    pub creation_time: Option<String>,
+    #[serde(skip_serializing)]
+    pub parent: Option<SnapshotParent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<SnapshotMemory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disks: Option<SnapshotDisks>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotParent {
+    pub name: String,
+}
+
+/// Whether and where the memory of a running domain is saved: `internal`,
+/// `external` to `file`, or `no`.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotMemory {
+    #[serde(rename = "@snapshot")]
+    pub snapshot: String,
+    #[serde(rename = "@file", skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotDisks {
+    #[serde(default, deserialize_with = "helpers::one_or_many")]
+    pub disk: Vec<SnapshotDisk>,
+}
+
+/// How one disk is snapshotted: `internal`, `external` to a new overlay in
+/// `source`, or `no`.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotDisk {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(rename = "@snapshot")]
+    pub snapshot: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+}
+
 // Cloud init related structs, meant to be serialized to user-data and meta-data files
 #[derive(Serialize, Deserialize)]
 pub struct CloudInitUserData {
@@ -179,6 +324,10 @@ pub struct CloudInitUserData {
     pub lock_passwd: bool,
     pub users: Vec<CloudInitUser>,
     pub ssh_pwauth: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runcmd: Vec<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -189,6 +338,8 @@ pub struct CloudInitUser {
     pub groups: Vec<String>,
     pub sudo: String,
     pub shell: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_authorized_keys: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -203,6 +354,131 @@ pub struct Ssh {
 
 #[derive(Serialize, Deserialize)]
 pub struct CloudInitMetaData {
+    /// Seeds written before the keys were renamed used `instance_id`, which
+    /// NoCloud ignores
+    #[serde(rename = "instance-id", alias = "instance_id")]
     pub instance_id: String,
+    #[serde(rename = "local-hostname", alias = "local_hostname")]
     pub local_hostname: String,
 }
+
+/// vm-alloc's own record of a VM, kept in the `<metadata>` element of the domain.
+#[derive(Serialize, Deserialize)]
+pub struct VmMetadata {
+    pub username: String,
+    /// Private key whose public half was injected through cloud-init
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<String>,
+    #[serde(
+        rename = "tag",
+        default,
+        deserialize_with = "helpers::one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub tags: Vec<Tag>,
+    /// RFC 3339 timestamp of the `create` run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    /// Base image the root disk was created from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// How `startup` orders the VM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup: Option<Startup>,
+}
+
+/// Position of a VM in the boot order of `startup`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Startup {
+    /// Seconds to wait after booting the VM before booting the next one
+    #[serde(rename = "@delay", skip_serializing_if = "Option::is_none")]
+    pub delay: Option<String>,
+    /// VMs that have to be booted first
+    #[serde(
+        default,
+        deserialize_with = "helpers::one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub after: Vec<StartupDependency>,
+}
+
+/// A VM `startup` boots before the one declaring it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StartupDependency {
+    #[serde(rename = "@name")]
+    pub name: String,
+}
+
+/// A `key=value` label on a VM. Bare tags have an empty value.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tag {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(rename = "#text", default)]
+    pub value: String,
+}
+
+// Output structs of `vm-info`, meant to be serialized to JSON or YAML
+#[derive(Serialize)]
+pub struct VmInfo {
+    pub name: String,
+    pub uuid: String,
+    pub id: Option<u32>,
+    pub state: String,
+    pub state_reason: String,
+    pub persistent: bool,
+    pub autostart: bool,
+    pub memory_kib: u64,
+    pub max_memory_kib: u64,
+    pub vcpus: u32,
+    pub max_vcpus: u32,
+    pub disks: Vec<DiskInfo>,
+    pub nics: Vec<NicInfo>,
+    pub graphics: Option<GraphicsInfo>,
+    pub username: Option<String>,
+    pub image: Option<String>,
+    pub created_at: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct DiskInfo {
+    pub target: String,
+    pub device: String,
+    pub source: Option<String>,
+    pub capacity_bytes: Option<u64>,
+    pub allocation_bytes: Option<u64>,
+    pub backing_file: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct NicInfo {
+    pub mac: Option<String>,
+    pub interface_type: String,
+    pub source: Option<String>,
+    pub model: Option<String>,
+    pub addresses: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct GraphicsInfo {
+    pub graphics_type: String,
+    pub listen: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// One row of `list`
+#[derive(Serialize)]
+pub struct VmSummary {
+    pub name: String,
+    pub state: String,
+    pub vcpus: u32,
+    pub memory_kib: u64,
+    pub addresses: Vec<String>,
+    pub image: Option<String>,
+    pub created_at: Option<String>,
+    /// Seconds since `created_at`
+    pub age_seconds: Option<u64>,
+    pub autostart: bool,
+    pub tags: Vec<String>,
+}
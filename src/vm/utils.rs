@@ -1,15 +1,149 @@
 use crate::helpers;
+use crate::vm::CreateVmOptions;
 use crate::vm::types;
 use serde_yml;
 use sha_crypt::{Sha512Params, sha512_simple};
+use std::ffi::{CString, c_int};
 use std::path::Path;
 use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
+use virt::domain::Domain;
+use virt::error::Error;
+
+/// Directory for state vm-alloc keeps outside of libvirt, like its SSH key.
+pub const STATE_DIR: &str = "/var/lib/vm-alloc";
+
+/// Namespace of the vm-alloc element in the domain `<metadata>`.
+pub const METADATA_URI: &str = "https://github.com/open-ug/vm-alloc";
+
+/// Directory holding a log directory per VM.
+pub const LOG_DIR: &str = "/var/log/vm-alloc";
+
+pub fn serial_log_path(name: &str) -> String {
+    format!("{}/{}/serial.log", LOG_DIR, name)
+}
+
+/// Create the log directory of a VM, which QEMU does not do by itself.
+pub fn create_log_dir(name: &str) {
+    if let Some(log_dir) = Path::new(&serial_log_path(name)).parent() {
+        std::fs::create_dir_all(log_dir).expect("Failed to create VM log directory.");
+    }
+}
+
+pub fn known_hosts_path() -> String {
+    format!("{}/known_hosts", STATE_DIR)
+}
+
+/// Return the private key vm-alloc injects into VMs by default, generating it
+/// on first use.
+pub fn ensure_ssh_key() -> String {
+    let key_path = format!("{}/id_ed25519", STATE_DIR);
+    if Path::new(&key_path).exists() {
+        return key_path;
+    }
+
+    std::fs::create_dir_all(STATE_DIR).expect("Failed to create vm-alloc state directory.");
+    println!("Generating SSH key {}", &key_path);
+    let status = Command::new("ssh-keygen")
+        .args([
+            "-q", "-t", "ed25519", "-N", "", "-C", "vm-alloc", "-f", &key_path,
+        ])
+        .status()
+        .expect("Failed to execute ssh-keygen command");
+    if !status.success() {
+        eprintln!("Error generating SSH key {}", &key_path);
+        std::process::exit(1);
+    }
+    key_path
+}
+
+/// Drop the host keys remembered for a VM. Entries are stored under the VM name
+/// (see `HostKeyAlias` in `ssh_vm`) so a re-created VM does not trip the check.
+pub fn forget_host_key(name: &str) {
+    if Path::new(&known_hosts_path()).exists() {
+        let _ = Command::new("ssh-keygen")
+            .args(["-R", name, "-f", &known_hosts_path()])
+            .output();
+    }
+}
+
+pub fn set_vm_metadata(domain: &Domain, metadata: &types::VmMetadata) -> Result<(), Error> {
+    let metadata_xml = helpers::struct_to_xml(metadata, "instance");
+    domain.set_metadata(
+        virt_sys::VIR_DOMAIN_METADATA_ELEMENT as i32,
+        Some(&metadata_xml),
+        Some("vmalloc"),
+        Some(METADATA_URI),
+        virt_sys::VIR_DOMAIN_AFFECT_CONFIG,
+    )?;
+    Ok(())
+}
+
+/// Read the vm-alloc metadata of a domain, `None` if it was not created by vm-alloc.
+pub fn get_vm_metadata(domain: &Domain) -> Option<types::VmMetadata> {
+    let metadata_xml = domain
+        .get_metadata(
+            virt_sys::VIR_DOMAIN_METADATA_ELEMENT as i32,
+            Some(METADATA_URI),
+            0,
+        )
+        .ok()?;
+    Some(helpers::xml_to_struct(&metadata_xml))
+}
+
+/// Cloud image new VMs are backed by.
+pub const DEFAULT_CLOUD_IMAGE: &str = "noble-server-cloudimg-amd64.img";
+
+/// Where `template create` puts its images.
+pub const TEMPLATE_DIR: &str = "/var/lib/libvirt/images/templates";
+
+pub fn template_path(template: &str) -> String {
+    format!("{}/{}.qcow2", TEMPLATE_DIR, template)
+}
+
+/// Path of the image to back a new VM by: a template of that name, or else a
+/// cloud image file in the image directory.
+pub fn resolve_image(image: &str) -> Option<String> {
+    [
+        template_path(image),
+        format!("/var/lib/libvirt/images/iso/{}", image),
+    ]
+    .into_iter()
+    .find(|path| Path::new(path).exists())
+}
+
+/// Virtual size in bytes of the disk image at `path`, per `qemu-img info`.
+fn image_virtual_size(path: &str) -> Result<u64, String> {
+    let output = Command::new("qemu-img")
+        .args(["info", "--output=json", path])
+        .output()
+        .map_err(|e| format!("could not run qemu-img: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("could not parse qemu-img info: {}", e))?;
+    info["virtual-size"]
+        .as_u64()
+        .ok_or_else(|| format!("qemu-img info has no virtual size for {}", path))
+}
+
+pub fn create_qemu_img_disk(name: &str, size_gb: u64, cloud_img: &str) -> Result<String, String> {
+    // qemu-img would shrink the overlay below its backing image, cutting off
+    // the end of the guest's disk
+    let backing_size = image_virtual_size(cloud_img)?;
+    if size_gb * 1024 * 1024 * 1024 < backing_size {
+        return Err(format!(
+            "{} has a virtual size of {}, a {}G disk cannot hold it",
+            cloud_img,
+            format_bytes(backing_size),
+            size_gb
+        ));
+    }
 
-pub fn create_qemu_img_disk(name: &str, size_gb: u64) -> String {
     let image_dir = "/var/lib/libvirt/images";
     let disk_path = format!("{}/{}.qcow2", image_dir, name);
-    let cloud_img = format!("{}/iso/noble-server-cloudimg-amd64.img", image_dir);
     let disk_path_obj = Path::new(&disk_path);
 
     // 2. Ensure the directory exists. This is necessary because the program is creating a file
@@ -33,21 +167,17 @@ pub fn create_qemu_img_disk(name: &str, size_gb: u64) -> String {
             "-F",
             "qcow2",
             "-b",
-            &cloud_img,
+            cloud_img,
             &disk_path,
             &format!("{}G", size_gb),
         ])
         .output()
         .expect("Failed to execute qemu-img command");
 
-    if output.status.success() {
-        println!("Disk image created successfully.");
-    } else {
-        eprintln!(
-            "Error creating disk image: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
     }
+    println!("Disk image created successfully.");
 
     println!("Changing ownership of {} to libvirt-qemu...", &disk_path);
     Command::new("chown")
@@ -56,21 +186,27 @@ pub fn create_qemu_img_disk(name: &str, size_gb: u64) -> String {
         .status()
         .expect("Failed to execute `chown` command. Please ensure you are running the program with `sudo`.");
 
-    format!("{}", disk_path)
+    Ok(format!("{}", disk_path))
 }
 
 pub fn generate_installation_domain_xml(
-    name: &str,
-    memory: u64,
-    vcpus: u8,
+    options: &CreateVmOptions,
     disk_path: String,
     seed_iso_path: String,
 ) -> String {
+    let name = options.name.as_str();
+    let memory = options.memory;
+    let vcpus = options.vcpus;
+
     let domain_uuid = Uuid::new_v4().to_string();
 
+    // Maxima above the current values leave room for hot resizing
+    let max_memory = options.max_memory.unwrap_or(memory);
+    let max_vcpus = options.max_vcpus.unwrap_or(vcpus);
+
     let memory_config = types::Memory {
         unit: "MiB".to_string(),
-        value: memory.to_string(),
+        value: max_memory.to_string(),
     };
     let current_memory_config = types::Memory {
         unit: "MiB".to_string(),
@@ -78,7 +214,8 @@ pub fn generate_installation_domain_xml(
     };
     let vcpu_config = types::Vcpu {
         placement: "static".to_string(),
-        value: vcpus.to_string(),
+        current: (max_vcpus > vcpus).then(|| vcpus.to_string()),
+        value: max_vcpus.to_string(),
     };
 
     let iso_disk = types::Disk {
@@ -90,13 +227,14 @@ pub fn generate_installation_domain_xml(
         }),
         source: Some(types::Source {
             file: Some(seed_iso_path),
-            bridge: None,
+            ..Default::default()
         }),
         target: Some(types::Target {
             dev: "hdb".to_string(),
             bus: "sata".to_string(),
         }),
         readonly: Some(types::Empty {}),
+        iotune: None,
     };
 
     let hd_disk = types::Disk {
@@ -108,30 +246,32 @@ pub fn generate_installation_domain_xml(
         }),
         source: Some(types::Source {
             file: Some(disk_path),
-            bridge: None,
+            ..Default::default()
         }),
         target: Some(types::Target {
             dev: "vda".to_string(),
             bus: "virtio".to_string(),
         }),
         readonly: None,
+        iotune: options.iotune.clone(),
     };
 
-    let interface = types::Interface {
-        interface_type: "bridge".to_string(),
-        source: Some(types::Source {
-            file: None,
-            bridge: Some("virbr0".to_string()),
-        }),
-        model: Some(types::Model {
-            model_type: "virtio".to_string(),
-        }),
+    let interfaces = if options.interfaces.is_empty() {
+        vec![parse_nic("bridge=virbr0").unwrap()]
+    } else {
+        options.interfaces.clone()
     };
 
-    let graphics = types::Graphics {
-        graphics_type: "vnc".to_string(),
-        port: "-1".to_string(),
-        autoport: "yes".to_string(),
+    // Headless VMs get no display at all, so they do not take up a port
+    let graphics = match options.graphics.as_str() {
+        "none" => None,
+        graphics_type => Some(types::Graphics {
+            graphics_type: graphics_type.to_string(),
+            port: Some("-1".to_string()),
+            autoport: "yes".to_string(),
+            listen: options.graphics_listen.clone(),
+            passwd: options.graphics_password.clone(),
+        }),
     };
 
     let console = types::Console {
@@ -148,14 +288,30 @@ pub fn generate_installation_domain_xml(
             type_: "isa-serial".to_string(),
             port: "0".to_string(),
         }),
+        log: Some(types::SerialLog {
+            file: serial_log_path(name),
+            append: Some("on".to_string()),
+        }),
     };
 
+    let mut channel = Vec::new();
+    if options.guest_agent {
+        channel.push(types::Channel {
+            channel_type: "unix".to_string(),
+            target: types::ChannelTarget {
+                type_: "virtio".to_string(),
+                name: Some("org.qemu.guest_agent.0".to_string()),
+            },
+        });
+    }
+
     let devices = types::Devices {
         disk: vec![iso_disk, hd_disk],
-        interface: Some(interface),
-        graphics: Some(graphics),
+        interface: interfaces,
+        graphics,
         console: Some(console),
         serial: Some(serial),
+        channel,
     };
 
     let domain_config = types::DomainConfig {
@@ -189,6 +345,648 @@ pub fn generate_installation_domain_xml(
     xml_element
 }
 
+/// Parse a `--nic` specification into an interface definition.
+///
+/// The first entry selects the type: `network=<name>`, `bridge=<name>`,
+/// `direct=<host dev>` or `user`. It may be followed by `model=<model>`,
+/// `mac=<address>` and, for `direct`, `mode=<macvtap mode>`.
+pub fn parse_nic(spec: &str) -> Result<types::Interface, String> {
+    let mut parts = spec.split(',');
+    let kind = parts.next().unwrap_or_default();
+    let (interface_type, value) = match kind.split_once('=') {
+        Some((t, v)) => (t, Some(v.to_string())),
+        None => (kind, None),
+    };
+
+    let mut source = match (interface_type, value) {
+        ("network", Some(network)) => Some(types::Source {
+            network: Some(network),
+            ..Default::default()
+        }),
+        ("bridge", Some(bridge)) => Some(types::Source {
+            bridge: Some(bridge),
+            ..Default::default()
+        }),
+        ("direct", Some(dev)) => Some(types::Source {
+            dev: Some(dev),
+            mode: Some("bridge".to_string()),
+            ..Default::default()
+        }),
+        ("user", None) => None,
+        _ => {
+            return Err(format!(
+                "invalid NIC type '{}', expected network=<name>, bridge=<name>, direct=<dev> or user",
+                kind
+            ));
+        }
+    };
+
+    let mut model = "virtio".to_string();
+    let mut mac = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("model", v)) => model = v.to_string(),
+            Some(("mac", v)) => {
+                if !is_valid_mac(v) {
+                    return Err(format!("invalid MAC address '{}'", v));
+                }
+                mac = Some(types::Mac {
+                    address: v.to_lowercase(),
+                });
+            }
+            Some(("mode", v)) if interface_type == "direct" => {
+                if let Some(source) = source.as_mut() {
+                    source.mode = Some(v.to_string());
+                }
+            }
+            _ => return Err(format!("invalid NIC option '{}'", part)),
+        }
+    }
+
+    Ok(types::Interface {
+        interface_type: interface_type.to_string(),
+        mac,
+        source,
+        model: Some(types::Model { model_type: model }),
+    })
+}
+
+/// Parse a `--tag` given as `key=value` or a bare `key`.
+pub fn parse_tag(tag: &str) -> Result<types::Tag, String> {
+    let (name, value) = tag.split_once('=').unwrap_or((tag, ""));
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("invalid tag '{}'", tag));
+    }
+    Ok(types::Tag {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Parse a disk size in bytes from a number with an optional binary `K`, `M`,
+/// `G` or `T` suffix. A bare number is in GB, like `--disk-size`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", size))?;
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "" | "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size unit in '{}'", size)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or(format!("size '{}' is too large", size))
+}
+
+fn is_valid_mac(mac: &str) -> bool {
+    let octets: Vec<&str> = mac.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Size of a `<memory>` element in KiB.
+pub fn memory_kib(memory: &types::Memory) -> u64 {
+    let value: u64 = memory.value.parse().unwrap_or(0);
+    match memory.unit.as_str() {
+        "b" | "bytes" => value / 1024,
+        "M" | "MiB" => value * 1024,
+        "G" | "GiB" => value * 1024 * 1024,
+        _ => value,
+    }
+}
+
+/// Pick the libvirt modification flags for a change to `domain`: the running
+/// instance if there is one, and the persistent config when asked for or when
+/// the domain is not running.
+pub fn modification_flags(domain: &Domain, persist: bool) -> u32 {
+    let mut flags = 0;
+    if domain.is_active().unwrap() {
+        flags |= virt_sys::VIR_DOMAIN_AFFECT_LIVE;
+    }
+    if persist || flags == 0 {
+        flags |= virt_sys::VIR_DOMAIN_AFFECT_CONFIG;
+    }
+    flags
+}
+
+/// An address of a guest NIC as reported by libvirt.
+pub struct GuestAddress {
+    pub interface: String,
+    pub mac: String,
+    pub address: String,
+    pub prefix: u64,
+    /// Where the address came from: `lease`, `arp` or `agent`
+    pub source: &'static str,
+}
+
+/// Look up the addresses of a running guest from the DHCP leases of libvirt
+/// networks, the host ARP table and the guest agent. Loopback and link-local
+/// addresses are skipped and each address is reported once.
+pub fn guest_addresses(domain: &Domain) -> Vec<GuestAddress> {
+    let sources = [
+        ("lease", virt_sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE),
+        ("arp", virt_sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_ARP),
+        ("agent", virt_sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_AGENT),
+    ];
+
+    let mut addresses: Vec<GuestAddress> = Vec::new();
+    for (source_name, source) in sources {
+        // Each source may be unavailable (no agent, no lease), which is not an error here
+        let Ok(interfaces) = domain.interface_addresses(source, 0) else {
+            continue;
+        };
+        for interface in interfaces {
+            for addr in interface.addrs {
+                let ip: std::net::IpAddr = match addr.addr.parse() {
+                    Ok(ip) => ip,
+                    Err(_) => continue,
+                };
+                let link_local = match ip {
+                    std::net::IpAddr::V4(v4) => v4.is_link_local(),
+                    std::net::IpAddr::V6(v6) => v6.is_unicast_link_local(),
+                };
+                if ip.is_loopback()
+                    || link_local
+                    || addresses.iter().any(|a| a.address == addr.addr)
+                {
+                    continue;
+                }
+                addresses.push(GuestAddress {
+                    interface: interface.name.clone(),
+                    mac: interface.hwaddr.clone(),
+                    address: addr.addr,
+                    prefix: addr.prefix,
+                    source: source_name,
+                });
+            }
+        }
+    }
+    addresses
+}
+
+/// Poll `guest_addresses` until the guest reports at least one address or
+/// `timeout` runs out, in which case the result is empty.
+pub fn wait_for_guest_addresses(domain: &Domain, timeout: Duration) -> Vec<GuestAddress> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let addresses = guest_addresses(domain);
+        if !addresses.is_empty() || Instant::now() >= deadline {
+            return addresses;
+        }
+        std::thread::sleep(Duration::from_secs(2));
+    }
+}
+
+/// Create the disk of a cloned VM at `path`: a qcow2 overlay on `source` when
+/// `linked`, otherwise a standalone copy of it and its backing chain.
+pub fn clone_disk(source: &str, path: &str, linked: bool) -> Result<(), String> {
+    let output = if linked {
+        Command::new("qemu-img")
+            .args(["create", "-f", "qcow2", "-F", "qcow2", "-b", source, path])
+            .output()
+    } else {
+        println!("Copying {} to {}...", source, path);
+        Command::new("qemu-img")
+            .args(["convert", "-O", "qcow2", source, path])
+            .output()
+    }
+    .map_err(|e| format!("could not run qemu-img: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Command::new("chown")
+        .arg("libvirt-qemu:libvirt-qemu")
+        .arg(path)
+        .status()
+        .map_err(|e| format!("could not run chown: {}", e))?;
+    Ok(())
+}
+
+/// Resize the disk image at `path` of a stopped VM to `size` bytes.
+pub fn resize_disk_image(path: &str, size: u64, shrink: bool) -> Result<(), String> {
+    let mut command = Command::new("qemu-img");
+    command.arg("resize");
+    if shrink {
+        command.arg("--shrink");
+    }
+    let output = command
+        .args([path, &size.to_string()])
+        .output()
+        .map_err(|e| format!("could not run qemu-img: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+/// Human readable name of a `virDomainState`.
+pub fn state_name(state: u32) -> &'static str {
+    match state {
+        virt_sys::VIR_DOMAIN_RUNNING => "running",
+        virt_sys::VIR_DOMAIN_BLOCKED => "blocked",
+        virt_sys::VIR_DOMAIN_PAUSED => "paused",
+        virt_sys::VIR_DOMAIN_SHUTDOWN => "shutting down",
+        virt_sys::VIR_DOMAIN_SHUTOFF => "shut off",
+        virt_sys::VIR_DOMAIN_CRASHED => "crashed",
+        virt_sys::VIR_DOMAIN_PMSUSPENDED => "suspended",
+        _ => "no state",
+    }
+}
+
+/// State as shown to users: `state_name`, except that stopped VMs with a
+/// managed save image are `saved`.
+pub fn display_state(domain: &Domain, state: u32) -> &'static str {
+    if state == virt_sys::VIR_DOMAIN_SHUTOFF && domain.has_managed_save(0).unwrap_or(false) {
+        "saved"
+    } else {
+        state_name(state)
+    }
+}
+
+/// Human readable reason for a state, as returned next to it by `get_state`.
+pub fn state_reason(state: u32, reason: i32) -> &'static str {
+    let reasons: &[&str] = match state {
+        virt_sys::VIR_DOMAIN_RUNNING => &[
+            "unknown",
+            "booted",
+            "migrated",
+            "restored",
+            "from snapshot",
+            "unpaused",
+            "migration canceled",
+            "save canceled",
+            "woken up",
+            "crashed",
+            "post-copy",
+            "post-copy failed",
+        ],
+        virt_sys::VIR_DOMAIN_PAUSED => &[
+            "unknown",
+            "user",
+            "migration",
+            "save",
+            "dump",
+            "I/O error",
+            "watchdog",
+            "from snapshot",
+            "shutting down",
+            "snapshot",
+            "crashed",
+            "starting up",
+            "post-copy",
+            "post-copy failed",
+        ],
+        virt_sys::VIR_DOMAIN_SHUTDOWN => &["unknown", "user"],
+        virt_sys::VIR_DOMAIN_SHUTOFF => &[
+            "unknown",
+            "shutdown",
+            "destroyed",
+            "crashed",
+            "migrated",
+            "saved",
+            "failed",
+            "from snapshot",
+            "daemon",
+        ],
+        virt_sys::VIR_DOMAIN_CRASHED => &["unknown", "panicked"],
+        _ => &["unknown"],
+    };
+    usize::try_from(reason)
+        .ok()
+        .and_then(|r| reasons.get(r))
+        .unwrap_or(&"unknown")
+}
+
+/// Format a byte count with a binary unit, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+/// Backing file of a disk image according to `qemu-img info`.
+pub fn backing_file(path: &str) -> Option<String> {
+    let output = Command::new("qemu-img")
+        .args(["info", "-U", "--output=json", path])
+        .output()
+        .ok()?;
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    info["full-backing-filename"]
+        .as_str()
+        .or(info["backing-filename"].as_str())
+        .map(str::to_string)
+}
+
+/// Gather everything `vm-info` reports about a domain.
+pub fn collect_vm_info(domain: &Domain) -> types::VmInfo {
+    let info = domain.get_info().unwrap();
+    let (state, reason) = domain.get_state().unwrap();
+    let active = domain.is_active().unwrap();
+    let domain_config: types::DomainConfig =
+        helpers::xml_to_struct(&domain.get_xml_desc(0).unwrap());
+    let metadata = get_vm_metadata(domain);
+    let addresses = if active {
+        guest_addresses(domain)
+    } else {
+        Vec::new()
+    };
+
+    let devices = domain_config.devices;
+    let disks = devices
+        .iter()
+        .flat_map(|d| &d.disk)
+        .filter_map(|disk| {
+            let target = disk.target.as_ref()?.dev.clone();
+            let source = disk.source.as_ref().and_then(|s| s.file.clone());
+            let block_info = domain.get_block_info(&target, 0).ok();
+            Some(types::DiskInfo {
+                device: disk.device.clone(),
+                capacity_bytes: block_info.as_ref().map(|b| b.capacity),
+                allocation_bytes: block_info.as_ref().map(|b| b.allocation),
+                backing_file: match disk.device.as_str() {
+                    "disk" => source.as_deref().and_then(backing_file),
+                    _ => None,
+                },
+                source,
+                target,
+            })
+        })
+        .collect();
+
+    let nics = devices
+        .iter()
+        .flat_map(|d| &d.interface)
+        .map(|interface| {
+            let mac = interface.mac.as_ref().map(|m| m.address.clone());
+            let source = interface.source.as_ref().and_then(|s| {
+                s.network
+                    .clone()
+                    .or_else(|| s.bridge.clone())
+                    .or_else(|| s.dev.clone())
+            });
+            types::NicInfo {
+                addresses: addresses
+                    .iter()
+                    .filter(|a| {
+                        mac.as_deref()
+                            .is_some_and(|m| a.mac.eq_ignore_ascii_case(m))
+                    })
+                    .map(|a| format!("{}/{}", a.address, a.prefix))
+                    .collect(),
+                interface_type: interface.interface_type.clone(),
+                model: interface.model.as_ref().map(|m| m.model_type.clone()),
+                source,
+                mac,
+            }
+        })
+        .collect();
+
+    let graphics =
+        devices
+            .as_ref()
+            .and_then(|d| d.graphics.as_ref())
+            .map(|g| types::GraphicsInfo {
+                graphics_type: g.graphics_type.clone(),
+                listen: g.listen.clone(),
+                // -1 until a port is picked on start
+                port: g.port.as_deref().and_then(|p| p.parse().ok()),
+            });
+
+    types::VmInfo {
+        name: domain.get_name().unwrap(),
+        uuid: domain.get_uuid_string().unwrap(),
+        id: domain.get_id(),
+        state: display_state(domain, state).to_string(),
+        state_reason: state_reason(state, reason).to_string(),
+        persistent: domain.is_persistent().unwrap(),
+        autostart: match &metadata {
+            Some(metadata) => autostart_enabled(domain, metadata),
+            None => domain.get_autostart().unwrap_or(false),
+        },
+        memory_kib: info.memory,
+        max_memory_kib: info.max_mem,
+        vcpus: info.nr_virt_cpu,
+        max_vcpus: domain
+            .get_vcpus_flags(virt_sys::VIR_DOMAIN_VCPU_MAXIMUM)
+            .unwrap_or(info.nr_virt_cpu),
+        disks,
+        nics,
+        graphics,
+        username: metadata.as_ref().map(|m| m.username.clone()),
+        image: metadata.as_ref().and_then(|m| m.image.clone()),
+        created_at: metadata.as_ref().and_then(|m| m.created_at.clone()),
+        tags: metadata.map(|m| format_tags(&m.tags)).unwrap_or_default(),
+    }
+}
+
+/// Render tags as `key=value`, or just `key` for tags without a value.
+pub fn format_tags(tags: &[types::Tag]) -> Vec<String> {
+    tags.iter()
+        .map(|t| {
+            if t.value.is_empty() {
+                t.name.clone()
+            } else {
+                format!("{}={}", t.name, t.value)
+            }
+        })
+        .collect()
+}
+
+/// Whether a VM boots with the host, through libvirt or through `startup`
+/// for VMs with a startup order.
+pub fn autostart_enabled(domain: &Domain, metadata: &types::VmMetadata) -> bool {
+    metadata.startup.is_some() || domain.get_autostart().unwrap_or(false)
+}
+
+/// Whether `tags` contain `filter`. A filter without a value matches any value.
+pub fn tags_match(tags: &[types::Tag], filter: &types::Tag) -> bool {
+    tags.iter()
+        .any(|t| t.name == filter.name && (filter.value.is_empty() || t.value == filter.value))
+}
+
+/// Gather the cheap subset of `collect_vm_info` shown by `list`.
+pub fn collect_vm_summary(domain: &Domain) -> types::VmSummary {
+    let info = domain.get_info().unwrap();
+    let metadata = get_vm_metadata(domain);
+    let addresses = if domain.is_active().unwrap() {
+        guest_addresses(domain)
+            .into_iter()
+            .map(|a| a.address)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let created_at = metadata.as_ref().and_then(|m| m.created_at.clone());
+    let age_seconds = created_at
+        .as_deref()
+        .and_then(|c| humantime::parse_rfc3339(c).ok())
+        .and_then(|c| SystemTime::now().duration_since(c).ok())
+        .map(|age| age.as_secs());
+
+    types::VmSummary {
+        name: domain.get_name().unwrap(),
+        state: display_state(domain, info.state).to_string(),
+        vcpus: info.nr_virt_cpu,
+        memory_kib: info.memory,
+        addresses,
+        image: metadata.as_ref().and_then(|m| m.image.clone()),
+        created_at,
+        age_seconds,
+        autostart: match &metadata {
+            Some(metadata) => autostart_enabled(domain, metadata),
+            None => domain.get_autostart().unwrap_or(false),
+        },
+        tags: metadata.map(|m| format_tags(&m.tags)).unwrap_or_default(),
+    }
+}
+
+/// Format an age in its largest unit, e.g. `3d` or `42m`.
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        s if s >= 86400 => format!("{}d", s / 86400),
+        s if s >= 3600 => format!("{}h", s / 3600),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+pub enum ShutdownOutcome {
+    Graceful,
+    Forced,
+    NotRunning,
+    Failed(String),
+}
+
+/// Ask a domain to shut down with the given `virDomainShutdownFlagValues` and
+/// wait up to `timeout` for it to stop. If it is still running by then it is
+/// powered off when `force` is set, otherwise the shutdown counts as failed.
+/// A domain that rejects the shutdown request is never powered off.
+pub fn shutdown_domain(
+    domain: &Domain,
+    flags: u32,
+    timeout: Duration,
+    force: bool,
+) -> ShutdownOutcome {
+    match domain.is_active() {
+        Ok(true) => {}
+        Ok(false) => return ShutdownOutcome::NotRunning,
+        Err(e) => return ShutdownOutcome::Failed(e.to_string()),
+    }
+
+    // A rejected request, e.g. without a guest agent or ACPI, is reported as is
+    // rather than passed off as a timeout
+    if let Err(e) = domain.shutdown_flags(flags) {
+        return ShutdownOutcome::Failed(e.to_string());
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        if !domain.is_active().unwrap_or(true) {
+            return ShutdownOutcome::Graceful;
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    if !force {
+        return ShutdownOutcome::Failed(format!("still running after {:?}", timeout));
+    }
+
+    match domain.destroy() {
+        Ok(()) => ShutdownOutcome::Forced,
+        Err(e) => ShutdownOutcome::Failed(e.to_string()),
+    }
+}
+
+/// Collect the limits that are set in `iotune` as libvirt block IO tune parameters.
+fn iotune_params(iotune: &types::Iotune) -> Vec<(&'static str, u64)> {
+    let fields = [
+        ("total_bytes_sec", &iotune.total_bytes_sec),
+        ("read_bytes_sec", &iotune.read_bytes_sec),
+        ("write_bytes_sec", &iotune.write_bytes_sec),
+        ("total_iops_sec", &iotune.total_iops_sec),
+        ("read_iops_sec", &iotune.read_iops_sec),
+        ("write_iops_sec", &iotune.write_iops_sec),
+        ("total_bytes_sec_max", &iotune.total_bytes_sec_max),
+        ("read_bytes_sec_max", &iotune.read_bytes_sec_max),
+        ("write_bytes_sec_max", &iotune.write_bytes_sec_max),
+        ("total_iops_sec_max", &iotune.total_iops_sec_max),
+        ("read_iops_sec_max", &iotune.read_iops_sec_max),
+        ("write_iops_sec_max", &iotune.write_iops_sec_max),
+    ];
+
+    fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value
+                .as_ref()
+                .map(|v| (name, v.parse().expect("IO tune values must be integers")))
+        })
+        .collect()
+}
+
+/// Apply IO limits to a disk of a domain through virDomainSetBlockIoTune, which
+/// the `virt` crate does not wrap.
+pub fn set_block_io_tune(
+    domain: &Domain,
+    dev: &str,
+    iotune: &types::Iotune,
+    flags: u32,
+) -> Result<(), Error> {
+    let mut params: virt_sys::virTypedParameterPtr = std::ptr::null_mut();
+    let mut nparams: c_int = 0;
+    let mut maxparams: c_int = 0;
+
+    for (name, value) in iotune_params(iotune) {
+        let name = CString::new(name).unwrap();
+        let ret = unsafe {
+            virt_sys::virTypedParamsAddULLong(
+                &mut params,
+                &mut nparams,
+                &mut maxparams,
+                name.as_ptr(),
+                value,
+            )
+        };
+        if ret == -1 {
+            unsafe { virt_sys::virTypedParamsFree(params, nparams) };
+            return Err(Error::last_error());
+        }
+    }
+
+    let dev = CString::new(dev).unwrap();
+    let ret = unsafe {
+        virt_sys::virDomainSetBlockIoTune(domain.as_ptr(), dev.as_ptr(), params, nparams, flags)
+    };
+    unsafe { virt_sys::virTypedParamsFree(params, nparams) };
+
+    if ret == -1 {
+        return Err(Error::last_error());
+    }
+    Ok(())
+}
+
 pub fn hash_password_sha512(password: &str) -> Result<String, sha_crypt::CryptError> {
     // Create params (choose rounds -- 10_000 is a reasonable default)
     let params = Sha512Params::new(10_000)?;
@@ -196,10 +994,16 @@ pub fn hash_password_sha512(password: &str) -> Result<String, sha_crypt::CryptEr
     sha512_simple(password, &params)
 }
 
-pub fn create_seed_iso(name: &str, username: &str, password: &str) -> String {
+pub fn create_seed_iso(
+    name: &str,
+    username: &str,
+    password: &str,
+    ssh_authorized_keys: Vec<String>,
+    guest_agent: bool,
+) -> Result<String, String> {
     let hashed_password = hash_password_sha512(password).unwrap();
 
-    let user_data = types::CloudInitUserData {
+    let mut user_data = types::CloudInitUserData {
         hostname: name.to_string(),
         locale: "en_US.UTF-8".to_string(),
         users: vec![types::CloudInitUser {
@@ -209,6 +1013,7 @@ pub fn create_seed_iso(name: &str, username: &str, password: &str) -> String {
             shell: "/bin/bash".to_string(),
             passwd: hashed_password,
             groups: vec!["sudo".to_string()],
+            ssh_authorized_keys,
         }],
         keyboard: types::Keyboard {
             layout: "us".to_string(),
@@ -218,10 +1023,22 @@ pub fn create_seed_iso(name: &str, username: &str, password: &str) -> String {
         ssh: types::Ssh {
             install_server: true,
         },
+        packages: Vec::new(),
+        runcmd: Vec::new(),
     };
 
+    if guest_agent {
+        user_data.packages.push("qemu-guest-agent".to_string());
+        user_data.runcmd.push(
+            ["systemctl", "enable", "--now", "qemu-guest-agent"]
+                .map(String::from)
+                .to_vec(),
+        );
+    }
+
     let meta_data = types::CloudInitMetaData {
-        instance_id: format!("{}-instance", name),
+        // A new instance-id makes cloud-init run again on cloned disks
+        instance_id: format!("{}-{}", name, Uuid::new_v4()),
         local_hostname: name.to_string(),
     };
     let meta_data_yaml =
@@ -236,6 +1053,17 @@ pub fn create_seed_iso(name: &str, username: &str, password: &str) -> String {
     println!("User Data YAML:\n{}", user_data_yaml);
     println!("Meta Data YAML:\n{}", meta_data_yaml);
 
+    let iso_path = write_seed_iso(name, &user_data_yaml, &meta_data_yaml)?;
+    println!("Seed ISO created successfully at {}", &iso_path);
+    Ok(iso_path)
+}
+
+/// Write the cloud-init NoCloud seed ISO of VM `name` and return its path.
+fn write_seed_iso(
+    name: &str,
+    user_data_yaml: &str,
+    meta_data_yaml: &str,
+) -> Result<String, String> {
     let iso_path = format!("/var/lib/libvirt/images/{}-seed.iso", name);
     let iso_path_obj = Path::new(&iso_path);
     if let Some(parent) = iso_path_obj.parent() {
@@ -258,18 +1086,54 @@ pub fn create_seed_iso(name: &str, username: &str, password: &str) -> String {
         .args(&[&iso_path, &user_data_file, &meta_data_file])
         .output()
         .expect("Failed to execute cloud-localds command");
-    if output.status.success() {
-        println!("Seed ISO created successfully at {}", &iso_path);
-    } else {
-        eprintln!(
-            "Error creating seed ISO: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        std::process::exit(1);
-    }
 
     // Clean up temporary files
     std::fs::remove_file(&user_data_file).expect("Unable to delete temporary user-data file");
     std::fs::remove_file(&meta_data_file).expect("Unable to delete temporary meta-data file");
-    iso_path
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(iso_path)
+}
+
+/// Read a file from a seed ISO.
+fn read_seed_file(iso: &str, file: &str) -> Result<String, String> {
+    let output = Command::new("isoinfo")
+        .args(["-R", "-i", iso, "-x", &format!("/{}", file)])
+        .output()
+        .map_err(|e| format!("could not run isoinfo: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Write the seed ISO of a VM renamed to `name` from its old seed ISO `iso`,
+/// with only the hostname changed. The instance-id cloud-init saw is kept so
+/// it does not provision the VM again; `update_hostname` applies the name on
+/// every boot. Returns the path of the new ISO.
+pub fn rename_seed_iso(iso: &str, name: &str) -> Result<String, String> {
+    let mut user_data: serde_yml::Value = serde_yml::from_str(&read_seed_file(iso, "user-data")?)
+        .map_err(|e| format!("invalid user-data: {}", e))?;
+    if let Some(user_data) = user_data.as_mapping_mut() {
+        user_data.insert("hostname".into(), name.into());
+    }
+    let meta_data_yaml = read_seed_file(iso, "meta-data")?;
+    let mut meta_data: types::CloudInitMetaData =
+        serde_yml::from_str(&meta_data_yaml).map_err(|e| format!("invalid meta-data: {}", e))?;
+    // NoCloud did not read the underscored key of older seeds and fell back
+    // to its default instance-id
+    if !meta_data_yaml.contains("instance-id:") {
+        meta_data.instance_id = "nocloud".to_string();
+    }
+    meta_data.local_hostname = name.to_string();
+
+    let user_data_yaml = format!(
+        "#cloud-config\n{}",
+        serde_yml::to_string(&user_data).expect("Failed to serialize user data to YAML")
+    );
+    let meta_data_yaml =
+        serde_yml::to_string(&meta_data).expect("Failed to serialize meta data to YAML");
+    write_seed_iso(name, &user_data_yaml, &meta_data_yaml)
 }